@@ -0,0 +1,686 @@
+use std::io::Error;
+
+use crate::state::{self, StateReader, StateWriter};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+pub const APU_CLOCK: u32 = 1_048_576; //m-cycles per second at normal speed, the apu always runs at this rate
+const MAX_BUFFERED_SECONDS: usize = 1; //samples nobody drains are dropped beyond this
+const HIGH_PASS_CHARGE: f32 = 0.999832; //per m-cycle, removes the dc offset like the hardware's capacitors
+
+const REGISTER_MASKS: [u8; 0x17] = [//bits that always read as 1, $FF10-$FF26
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+];
+const DUTY_PATTERNS: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+    max: u16, //64, or 256 for the wave channel
+}
+
+impl Length {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.bool(self.enabled);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.counter = reader.u16()?;
+        self.enabled = reader.bool()?;
+        Ok(())
+    }
+    fn load(&mut self, data: u8) {
+        self.counter = self.max - (data as u16 & (self.max - 1));
+    }
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+    fn clock(&mut self) -> bool {//returns true when the counter runs out and the channel turns off
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.initial_volume);
+        writer.bool(self.increase);
+        writer.u8(self.period);
+        writer.u8(self.volume);
+        writer.u8(self.timer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.initial_volume = reader.u8()? & 0x0F;
+        self.increase = reader.bool()?;
+        self.period = reader.u8()? & 0b00000111;
+        self.volume = reader.u8()? & 0x0F;
+        self.timer = reader.u8()? & 0b00000111;
+        Ok(())
+    }
+    fn write(&mut self, data: u8) {//NRx2
+        self.initial_volume = data >> 4;
+        self.increase = data & 0b00001000 != 0;
+        self.period = data & 0b00000111;
+    }
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            }
+            else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Square {//channels 1 and 2, only channel 1 uses the sweep
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: i32, //t-cycles until the next duty step
+    length: Length,
+    envelope: Envelope,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    sweep_negated: bool, //a subtraction happened since the last trigger
+    shadow_frequency: u16,
+}
+
+impl Square {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.duty);
+        writer.u8(self.duty_step);
+        writer.u16(self.frequency);
+        writer.i32(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.u8(self.sweep_period);
+        writer.bool(self.sweep_negate);
+        writer.u8(self.sweep_shift);
+        writer.u8(self.sweep_timer);
+        writer.bool(self.sweep_enabled);
+        writer.bool(self.sweep_negated);
+        writer.u16(self.shadow_frequency);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.duty = reader.u8()? & 0b11;
+        self.duty_step = reader.u8()? & 7;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.i32()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.sweep_period = reader.u8()? & 0b00000111;
+        self.sweep_negate = reader.bool()?;
+        self.sweep_shift = reader.u8()? & 0b00000111;
+        self.sweep_timer = reader.u8()? & 0b00001111;
+        self.sweep_enabled = reader.bool()?;
+        self.sweep_negated = reader.bool()?;
+        self.shadow_frequency = reader.u16()? & 0x7FF;
+        state::check((0..=2048 * 4).contains(&self.timer), "square channel timer") //the period can shrink after the timer was loaded, so check against the longest
+    }
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) & 7;
+        }
+    }
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        ((DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_step)) & 1) * self.envelope.volume
+    }
+    fn write_sweep(&mut self, data: u8) {//NR10
+        self.sweep_period = (data >> 4) & 0b00000111;
+        self.sweep_negate = data & 0b00001000 != 0;
+        self.sweep_shift = data & 0b00000111;
+        if !self.sweep_negate && self.sweep_negated {//leaving negate mode after using it disables the channel
+            self.enabled = false;
+        }
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        self.sweep_negated = false;
+        if self.sweep_shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+    fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.frequency = frequency;
+                self.shadow_frequency = frequency;
+                self.sweep_frequency(); //checked for overflow a second time, but not written
+            }
+        }
+    }
+    fn sweep_frequency(&mut self) -> u16 {//next frequency, disables the channel when it overflows
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.sweep_negated = true;
+            self.shadow_frequency - delta
+        }
+        else {
+            self.shadow_frequency + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+}
+
+#[derive(Default)]
+struct Wave {//channel 3
+    enabled: bool,
+    dac_enabled: bool,
+    volume_shift: u8, //from NR32, 4 mutes the channel
+    frequency: u16,
+    timer: i32,
+    position: u8, //0-31, nibble of wave ram being played
+    sample: u8,
+    length: Length,
+    ram: [u8; 16], //$FF30-$FF3F
+}
+
+impl Wave {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.volume_shift);
+        writer.u16(self.frequency);
+        writer.i32(self.timer);
+        writer.u8(self.position);
+        writer.u8(self.sample);
+        self.length.save_state(writer);
+        writer.bytes(&self.ram);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.volume_shift = reader.u8()?;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.i32()?;
+        self.position = reader.u8()? & 31;
+        self.sample = reader.u8()? & 0x0F;
+        self.length.load_state(reader)?;
+        reader.bytes(&mut self.ram)?;
+        state::check(matches!(self.volume_shift, 0 | 1 | 2 | 4), "wave volume")?;
+        state::check((0..=2048 * 2).contains(&self.timer), "wave channel timer")
+    }
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 31;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        self.sample >> self.volume_shift
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+}
+
+#[derive(Default)]
+struct Noise {//channel 4
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    short_mode: bool, //7-bit lfsr instead of 15-bit
+    divisor_code: u8,
+    timer: i32,
+    lfsr: u16,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.clock_shift);
+        writer.bool(self.short_mode);
+        writer.u8(self.divisor_code);
+        writer.i32(self.timer);
+        writer.u16(self.lfsr);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.clock_shift = reader.u8()? & 0x0F;
+        self.short_mode = reader.bool()?;
+        self.divisor_code = reader.u8()? & 7;
+        self.timer = reader.i32()?;
+        self.lfsr = reader.u16()? & 0x7FFF;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        state::check((0..=NOISE_DIVISORS[7] << 15).contains(&self.timer), "noise channel timer")
+    }
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let feedback = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+}
+
+pub struct Apu {
+    registers: [u8; 0x17], //raw values of $FF10-$FF26, for reads
+    powered: bool, //NR52 bit 7
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    frame_step: u8, //frame sequencer position, 0-7
+    leftover_cycles: u32, //t-cycles short of a whole m-cycle
+    sample_rate: u32,
+    sample_phase: u32,
+    left_sum: f32,
+    right_sum: f32,
+    sum_count: u32,
+    left_capacitor: f32,
+    right_capacitor: f32,
+    samples: Vec<f32>, //interleaved left and right
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Apu {
+        let mut apu = Apu {
+            registers: [0; 0x17],
+            powered: true,
+            square1: Default::default(),
+            square2: Default::default(),
+            wave: Default::default(),
+            noise: Default::default(),
+            frame_step: 0,
+            leftover_cycles: 0,
+            sample_rate,
+            sample_phase: 0,
+            left_sum: 0.0,
+            right_sum: 0.0,
+            sum_count: 0,
+            left_capacitor: 0.0,
+            right_capacitor: 0.0,
+            samples: Vec::new(),
+        };
+        apu.square1.length.max = 64;
+        apu.square2.length.max = 64;
+        apu.wave.length.max = 256;
+        apu.noise.length.max = 64;
+
+        //emulates behavior of DMG after the boot rom
+        for (address, data) in [(0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF14, 0xBF), (0xFF16, 0x3F),
+                                (0xFF19, 0xBF), (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1E, 0xBF),
+                                (0xFF20, 0xFF), (0xFF23, 0xBF), (0xFF24, 0x77), (0xFF25, 0xF3)] {
+            apu.write(address, data);
+        }
+        apu.square1.enabled = true; //left on by the boot sound
+        apu
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {//the sample rate and unread samples belong to the frontend
+        writer.bytes(&self.registers);
+        writer.bool(self.powered);
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.u8(self.frame_step);
+        writer.u32(self.leftover_cycles);
+        writer.f32(self.left_capacitor);
+        writer.f32(self.right_capacitor);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        reader.bytes(&mut self.registers)?;
+        self.powered = reader.bool()?;
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.frame_step = reader.u8()? & 7;
+        self.leftover_cycles = reader.u32()?;
+        state::check(self.leftover_cycles < 4, "apu cycle counter")?;
+        self.left_capacitor = reader.f32()?;
+        self.right_capacitor = reader.f32()?;
+        self.sample_phase = 0;
+        self.left_sum = 0.0;
+        self.right_sum = 0.0;
+        self.sum_count = 0;
+        self.samples.clear();
+        Ok(())
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_phase = 0;
+        self.samples.clear();
+    }
+    pub fn drain_samples(&mut self, output: &mut Vec<f32>) {//moves out every sample produced since the last call
+        output.append(&mut self.samples);
+    }
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                let mut status = (self.powered as u8) << 7 | REGISTER_MASKS[0x16];
+                let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+                for (i, enabled) in channels.iter().enumerate() {
+                    status |= (*enabled as u8) << i;
+                }
+                status
+            }
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.registers[index] | REGISTER_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.wave.ram[(address - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, address: u16, data: u8) {
+        if let 0xFF30..=0xFF3F = address {
+            self.wave.ram[(address - 0xFF30) as usize] = data;
+            return;
+        }
+        if address == 0xFF26 {
+            self.write_power(data & 0b10000000 != 0);
+            return;
+        }
+        if !self.powered || !(0xFF10..=0xFF25).contains(&address) {//registers are read-only while powered off
+            return;
+        }
+        self.registers[(address - 0xFF10) as usize] = data;
+
+        match address {
+            0xFF10 => self.square1.write_sweep(data),
+            0xFF11 => {
+                self.square1.duty = data >> 6;
+                self.square1.length.load(data);
+            }
+            0xFF12 => {
+                self.square1.envelope.write(data);
+                self.square1.dac_enabled = data & 0xF8 != 0;
+                self.square1.enabled &= self.square1.dac_enabled;
+            }
+            0xFF13 => self.square1.frequency = (self.square1.frequency & 0x700) | data as u16,
+            0xFF14 => {
+                self.square1.frequency = (self.square1.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.square1.length.enabled = data & 0b01000000 != 0;
+                if data & 0b10000000 != 0 {
+                    self.square1.trigger();
+                }
+            }
+            0xFF16 => {
+                self.square2.duty = data >> 6;
+                self.square2.length.load(data);
+            }
+            0xFF17 => {
+                self.square2.envelope.write(data);
+                self.square2.dac_enabled = data & 0xF8 != 0;
+                self.square2.enabled &= self.square2.dac_enabled;
+            }
+            0xFF18 => self.square2.frequency = (self.square2.frequency & 0x700) | data as u16,
+            0xFF19 => {
+                self.square2.frequency = (self.square2.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.square2.length.enabled = data & 0b01000000 != 0;
+                if data & 0b10000000 != 0 {
+                    self.square2.trigger();
+                }
+            }
+            0xFF1A => {
+                self.wave.dac_enabled = data & 0b10000000 != 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            }
+            0xFF1B => self.wave.length.load(data),
+            0xFF1C => {
+                self.wave.volume_shift = match (data >> 5) & 0b11 {
+                    0 => 4,
+                    1 => 0,
+                    2 => 1,
+                    _ => 2,
+                };
+            }
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | data as u16,
+            0xFF1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.wave.length.enabled = data & 0b01000000 != 0;
+                if data & 0b10000000 != 0 {
+                    self.wave.trigger();
+                }
+            }
+            0xFF20 => self.noise.length.load(data),
+            0xFF21 => {
+                self.noise.envelope.write(data);
+                self.noise.dac_enabled = data & 0xF8 != 0;
+                self.noise.enabled &= self.noise.dac_enabled;
+            }
+            0xFF22 => {
+                self.noise.clock_shift = data >> 4;
+                self.noise.short_mode = data & 0b00001000 != 0;
+                self.noise.divisor_code = data & 0b00000111;
+            }
+            0xFF23 => {
+                self.noise.length.enabled = data & 0b01000000 != 0;
+                if data & 0b10000000 != 0 {
+                    self.noise.trigger();
+                }
+            }
+            _ => {} //NR50 and NR51 are only read back from registers
+        }
+    }
+    pub fn clock_frame_sequencer(&mut self) {//512 Hz, driven by a falling edge of a div bit
+        if !self.powered {
+            return;
+        }
+        if self.frame_step & 1 == 0 {//256 Hz length counters
+            if self.square1.length.clock() {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.clock() {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {//128 Hz sweep
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {//64 Hz envelopes
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 7;
+    }
+    pub fn tick(&mut self, t_cycles: u32) {
+        self.leftover_cycles += t_cycles;
+        while self.leftover_cycles >= 4 {
+            self.leftover_cycles -= 4;
+            if self.powered {
+                self.square1.step(4);
+                self.square2.step(4);
+                self.wave.step(4);
+                self.noise.step(4);
+            }
+
+            let (left, right) = self.mix();
+            self.left_sum += left;
+            self.right_sum += right;
+            self.sum_count += 1;
+
+            self.sample_phase += self.sample_rate;
+            if self.sample_phase >= APU_CLOCK {//average everything since the last sample
+                self.sample_phase -= APU_CLOCK;
+                self.push_sample();
+            }
+        }
+    }
+
+    fn write_power(&mut self, powered: bool) {
+        if self.powered && !powered {//powering off clears every register but wave ram
+            for address in 0xFF10..=0xFF25 {
+                self.write(address, 0);
+            }
+            self.square1.enabled = false;
+            self.square2.enabled = false;
+            self.wave.enabled = false;
+            self.noise.enabled = false;
+        }
+        else if !self.powered && powered {
+            self.frame_step = 0;
+            self.square1.duty_step = 0;
+            self.square2.duty_step = 0;
+            self.wave.sample = 0;
+        }
+        self.powered = powered;
+    }
+    fn mix(&mut self) -> (f32, f32) {
+        let panning = self.registers[0x15]; //NR51
+        let volume = self.registers[0x14]; //NR50
+        let outputs = [
+            dac_output(self.square1.dac_enabled, self.square1.output()),
+            dac_output(self.square2.dac_enabled, self.square2.output()),
+            dac_output(self.wave.dac_enabled, self.wave.output()),
+            dac_output(self.noise.dac_enabled, self.noise.output()),
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if panning & (0b00010000 << i) != 0 {
+                left += output;
+            }
+            if panning & (0b00000001 << i) != 0 {
+                right += output;
+            }
+        }
+        left *= (((volume >> 4) & 0b111) + 1) as f32 / 8.0;
+        right *= ((volume & 0b111) + 1) as f32 / 8.0;
+
+        let left_out = left / 4.0 - self.left_capacitor;
+        self.left_capacitor = left / 4.0 - left_out * HIGH_PASS_CHARGE;
+        let right_out = right / 4.0 - self.right_capacitor;
+        self.right_capacitor = right / 4.0 - right_out * HIGH_PASS_CHARGE;
+        (left_out, right_out)
+    }
+    fn push_sample(&mut self) {
+        if self.sum_count == 0 {
+            return;
+        }
+        if self.samples.len() >= self.sample_rate as usize * 2 * MAX_BUFFERED_SECONDS {
+            self.samples.clear();
+        }
+        self.samples.push(self.left_sum / self.sum_count as f32);
+        self.samples.push(self.right_sum / self.sum_count as f32);
+        self.left_sum = 0.0;
+        self.right_sum = 0.0;
+        self.sum_count = 0;
+    }
+}
+
+fn dac_output(dac_enabled: bool, digital: u8) -> f32 {//maps 0-15 to 1.0 through -1.0, silent when the dac is off
+    if !dac_enabled {
+        return 0.0;
+    }
+    1.0 - digital as f32 / 7.5
+}
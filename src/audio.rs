@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+const DEVICE_SAMPLE_RATE: i32 = 48000;
+const DEVICE_BUFFER_FRAMES: u16 = 1024;
+const TARGET_LATENCY: f64 = 0.06; //seconds of audio kept queued ahead of the device
+const MAX_LATENCY: f64 = 0.25; //beyond this the queue is dropped rather than letting lag build up
+const MAX_RATE_ADJUSTMENT: f64 = 0.005; //dynamic rate control bends the pitch by at most 0.5%
+const BYTES_PER_FRAME: u32 = 8; //two f32 channels
+
+pub struct Resampler {//box-filter decimation from the apu rate down to the device rate
+    input_rate: f64,
+    output_rate: f64,
+    phase: f64, //fraction of an output frame accumulated so far
+    left_sum: f32,
+    right_sum: f32,
+    count: u32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Resampler {
+        Resampler {
+            input_rate: input_rate as f64,
+            output_rate: output_rate as f64,
+            phase: 0.0,
+            left_sum: 0.0,
+            right_sum: 0.0,
+            count: 0,
+        }
+    }
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>, adjustment: f64) {//adjustment scales the output rate, 1.0 is exact
+        let step = self.output_rate * adjustment / self.input_rate;
+        for frame in input.chunks_exact(2) {
+            self.left_sum += frame[0];
+            self.right_sum += frame[1];
+            self.count += 1;
+            self.phase += step;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                output.push(self.left_sum / self.count as f32);
+                output.push(self.right_sum / self.count as f32);
+                self.left_sum = 0.0;
+                self.right_sum = 0.0;
+                self.count = 0;
+            }
+        }
+    }
+}
+
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    resampler: Resampler,
+    output: Vec<f32>, //resampled frames, reused between calls
+    speed: f64, //emulation speed multiplier, raises or lowers the pitch to match
+}
+
+impl Audio {
+    pub fn new(subsystem: &AudioSubsystem, input_rate: u32) -> Result<Audio, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(DEVICE_SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(DEVICE_BUFFER_FRAMES),
+        };
+        let queue: AudioQueue<f32> = subsystem.open_queue(None, &desired)?;
+        let device_rate = queue.spec().freq as u32;
+        queue.resume();
+        Ok(Audio {
+            queue,
+            resampler: Resampler::new(input_rate, device_rate),
+            output: Vec::new(),
+            speed: 1.0,
+        })
+    }
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+    pub fn queue(&mut self, samples: &[f32]) {//takes interleaved stereo samples at the apu rate
+        let queued = self.queued_seconds();
+        if queued > MAX_LATENCY {
+            self.queue.clear();
+        }
+
+        //a short queue gets slightly more output frames per input to refill it, a long one slightly fewer to drain it
+        let fill = (TARGET_LATENCY - queued) / TARGET_LATENCY;
+        let adjustment = (1.0 + fill.clamp(-1.0, 1.0) * MAX_RATE_ADJUSTMENT) / self.speed;
+
+        self.output.clear();
+        self.resampler.process(samples, &mut self.output, adjustment);
+        if let Err(error) = self.queue.queue_audio(&self.output) {
+            println!("Failed to queue audio: {}", error);
+        }
+    }
+    pub fn wait(&self) {//blocks until the device has played down to the target latency, for syncing to audio
+        while self.queued_seconds() > TARGET_LATENCY {
+            spin_sleep::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn queued_seconds(&self) -> f64 {
+        (self.queue.size() / BYTES_PER_FRAME) as f64 / self.queue.spec().freq as f64
+    }
+}
@@ -1,210 +1,706 @@
+use std::io::Error;
+use std::path::Path;
+
 use crate::Registers;
 use crate::Rom;
-use crate::Screen;
 use crate::registers::RegisterName;
+use crate::interrupts::Interrupt;
+use crate::joypad::Button;
+use crate::mmu::Mmu;
+use crate::state::{self, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 
 const ZERO_FLAG: u8 = 0b10000000;
 const SUB_FLAG: u8 = 0b01000000;
 const HALF_CARRY_FLAG: u8 = 0b00100000;
 const CARRY_FLAG: u8 = 0b00010000;
-
-pub struct Mbc {
-    mbc_type: u8,
-    active_bank: u16,
-}
+const ROM_ID: std::ops::Range<usize> = 0x014D..0x0150; //header and global checksums, to match states to their game
 
 pub struct Cpu {
-    rom: Rom,
     registers: Registers,
-    mbc: Mbc,
+    mmu: Mmu,
     opcode: u8,
+    ime: bool,
+    ime_scheduled: bool, //ei takes effect after the following instruction
+    halted: bool,
+    halt_bug: bool,
+    stopped: bool,
+    double_speed: bool, //cgb only
+    speed_switch_armed: bool, //bit 0 of KEY1
+    state_size: usize, //length of every save state for this cartridge
 }
 
 impl Cpu {
     pub fn new(rom: Rom) -> Cpu {
         let opcode = rom.data[0x0100];
-        let mbc_type = rom.cartridge_type;
-        Cpu {
-            rom,
+        let mut cpu = Cpu {
             registers: Registers::new(),
-            mbc: Mbc {
-                mbc_type,
-                active_bank: 1,
-            },
-            opcode
+            mmu: Mmu::new(rom),
+            opcode,
+            ime: false,
+            ime_scheduled: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            state_size: 0,
+        };
+        cpu.state_size = cpu.save_state().len();
+        cpu
+    }
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.mmu.ppu.framebuffer
+    }
+    pub fn poll_frame(&mut self) -> bool {//true once the ppu has finished a frame
+        self.mmu.ppu.poll_frame()
+    }
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.mmu.interrupts.request(interrupt);
+    }
+    pub fn set_rtc_wall_clock(&mut self, enabled: bool) {
+        self.mmu.set_rtc_wall_clock(enabled);
+    }
+    pub fn load_save(&mut self, path: &Path) -> Result<(), Error> {//battery-backed cartridge ram
+        self.mmu.load_save(path)
+    }
+    pub fn write_save(&mut self, path: &Path) -> Result<(), Error> {
+        self.mmu.write_save(path)
+    }
+    pub fn save_dirty(&self) -> bool {
+        self.mmu.save_dirty()
+    }
+    pub fn save_state(&self) -> Vec<u8> {//the whole machine, for save states and rewind
+        let mut writer = StateWriter::new();
+        writer.bytes(&STATE_MAGIC);
+        writer.u32(STATE_VERSION);
+        writer.bytes(&self.mmu.rom().data[ROM_ID]);
+        self.registers.save_state(&mut writer);
+        writer.u8(self.opcode);
+        for flag in [self.ime, self.ime_scheduled, self.halted, self.halt_bug, self.stopped, self.double_speed, self.speed_switch_armed] {
+            writer.bool(flag);
         }
+        self.mmu.save_state(&mut writer);
+        writer.into_bytes()
+    }
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {//for states read from disk, rolls back if one turns out to be corrupt
+        let backup = self.save_state();
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup).expect("Failed to restore the state from before a failed load");
+        }
+        result
+    }
+    pub fn load_snapshot(&mut self, data: &[u8]) -> Result<(), Error> {//for states saved earlier this session, skips the backup
+        self.read_state(data)
+    }
+    pub fn poll_rumble(&mut self) -> Option<bool> {//rumble motor events for the frontend
+        self.mmu.poll_rumble()
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.mmu.sample_rate()
     }
-    pub fn exec(&mut self, screen: &mut Screen) -> i32 {//returns number of m-cycles to delay
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mmu.set_sample_rate(sample_rate);
+    }
+    pub fn drain_samples(&mut self, output: &mut Vec<f32>) {//interleaved stereo samples produced by the apu
+        self.mmu.drain_samples(output);
+    }
+    pub fn press_button(&mut self, button: Button) {
+        self.mmu.press_button(button);
+    }
+    pub fn release_button(&mut self, button: Button) {
+        self.mmu.release_button(button);
+    }
+    pub fn exec(&mut self) -> i32 {//returns number of m-cycles the instruction took
+        let duration = self.step();
+        self.mmu.tick(duration as u32, self.double_speed);
+        duration
+    }
+    fn step(&mut self) -> i32 {
+        if self.stopped {//only a new button press wakes the cpu from stop, a stale joypad IF bit doesn't
+            if !self.mmu.button_pressed_since_stop() {
+                return 1;
+            }
+            self.stopped = false;
+        }
+        if self.halted {
+            if !self.mmu.interrupts.pending() {
+                return 1;
+            }
+            self.halted = false;
+        }
+        if self.ime {
+            if let Some(interrupt) = self.mmu.interrupts.highest_pending() {
+                return self.dispatch_interrupt(interrupt);
+            }
+        }
+        if self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+
         let current_pc = self.registers.read(RegisterName::PC);
-        let mut next_pc = current_pc; //override with jump instructions
+        let mut next_pc = current_pc;
+        let mut jumped = false; //set by jump instructions, which provide next_pc themselves
         let mut f = self.registers.read(RegisterName::F) as u8;
 
         let mut duration = 1; //most opcodes last 1 m-cycle
         let mut length = 1;
-        
-        println!("Executing opcode {:#04X?} at ${:04X?}", self.opcode, current_pc);
-        println!("{}", self.registers);
 
         match self.opcode {
             0x00 => {//nop
             },
-            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x3C => {//inc r8
-                let register = match_register_u8(self.opcode & 0b00111000 >> 3);
-                let r8 = self.registers.read(register);
-                self.registers.write(register, r8.wrapping_add(1));
+            0x01 | 0x11 | 0x21 | 0x31 => {//ld r16, imm16
+                let register = match_register_u16((self.opcode & 0b00110000) >> 4);
+                let imm16 = self.read_imm16(current_pc);
+                self.registers.write(register, imm16);
+
+                length = 3;
+                duration = 3;
+            },
+            0x02 | 0x12 | 0x22 | 0x32 => {//ld [r16], a
+                let address = self.indirect_address((self.opcode & 0b00110000) >> 4);
+                let a = self.registers.read(RegisterName::A) as u8;
+                self.write_to_memory(address, a);
+
+                duration = 2;
+            },
+            0x0A | 0x1A | 0x2A | 0x3A => {//ld a, [r16]
+                let address = self.indirect_address((self.opcode & 0b00110000) >> 4);
+                let data = self.read_from_memory(address);
+                self.registers.write(RegisterName::A, data as u16);
+
+                duration = 2;
+            },
+            0x03 | 0x13 | 0x23 | 0x33 => {//inc r16
+                let register = match_register_u16((self.opcode & 0b00110000) >> 4);
+                let r16 = self.registers.read(register);
+                self.registers.write(register, r16.wrapping_add(1));
+
+                duration = 2;
+            },
+            0x0B | 0x1B | 0x2B | 0x3B => {//dec r16
+                let register = match_register_u16((self.opcode & 0b00110000) >> 4);
+                let r16 = self.registers.read(register);
+                self.registers.write(register, r16.wrapping_sub(1));
+
+                duration = 2;
+            },
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {//inc r8
+                let op = (self.opcode & 0b00111000) >> 3;
+                let r8 = self.read_operand_u8(op);
+                self.write_operand_u8(op, r8.wrapping_add(1));
 
-                let mask = f & 0b00010000;
-                f = set_add_flags_u8(r8 as u8, 1, f);
-                if mask != 0 {
-                    f = f | mask;
+                let mask = f & CARRY_FLAG;
+                f = set_add_flags_u8(r8, 1, f);
+                f = (f & !CARRY_FLAG) | mask;
+
+                if op == 0b0110 {
+                    duration = 3;
                 }
-                else {
-                    f = f & 0b11101111;
+            },
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {//dec r8
+                let op = (self.opcode & 0b00111000) >> 3;
+                let r8 = self.read_operand_u8(op);
+                self.write_operand_u8(op, r8.wrapping_sub(1));
+
+                let mask = f & CARRY_FLAG;
+                f = set_sub_flags_u8(r8, 1, f);
+                f = (f & !CARRY_FLAG) | mask;
+
+                if op == 0b0110 {
+                    duration = 3;
                 }
-            }
-            0x05 | 0x15 | 0x25 | 0x0D | 0x1D | 0x2D | 0x3D => {//dec r8
-                let register = match_register_u8(self.opcode & 0b00111000 >> 3);
-                let r8 = self.registers.read(register);
-                self.registers.write(register, r8.wrapping_sub(1));
-
-                let mask = f & 0b00010000;
-                f = set_sub_flags_u8(r8 as u8, 1, f);
-                if mask != 0 {
-                    f = f | mask;
+            },
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {//ld r8, imm8
+                let op = (self.opcode & 0b00111000) >> 3;
+                let imm8 = self.read_from_memory(current_pc.wrapping_add(1));
+                self.write_operand_u8(op, imm8);
+
+                length = 2;
+                duration = if op == 0b0110 { 3 } else { 2 };
+            },
+            0x07 | 0x0F | 0x17 | 0x1F => {//rlca, rrca, rla, rra
+                let a = self.registers.read(RegisterName::A) as u8;
+                let (result, flags) = rotate_shift_u8((self.opcode & 0b00011000) >> 3, a, f);
+                self.registers.write(RegisterName::A, result as u16);
+
+                f = flags & !ZERO_FLAG; //the accumulator variants always clear z
+            },
+            0x08 => {//ld [imm16], sp
+                let address = self.read_imm16(current_pc);
+                let sp = self.registers.read(RegisterName::SP);
+                self.write_to_memory(address, sp as u8);
+                self.write_to_memory(address.wrapping_add(1), (sp >> 8) as u8);
+
+                length = 3;
+                duration = 5;
+            },
+            0x09 | 0x19 | 0x29 | 0x39 => {//add hl, r16
+                let register = match_register_u16((self.opcode & 0b00110000) >> 4);
+                let hl = self.registers.read(RegisterName::HL);
+                let r16 = self.registers.read(register);
+                self.registers.write(RegisterName::HL, hl.wrapping_add(r16));
+
+                f &= ZERO_FLAG;
+                if (hl & 0x0FFF) + (r16 & 0x0FFF) > 0x0FFF {
+                    f |= HALF_CARRY_FLAG;
+                }
+                if hl as u32 + r16 as u32 > 0xFFFF {
+                    f |= CARRY_FLAG;
+                }
+
+                duration = 2;
+            },
+            0x10 => {//stop
+                self.write_to_memory(0xFF04, 0); //stop resets div
+                if self.mmu.rom().is_cgb() && self.speed_switch_armed {
+                    self.double_speed = !self.double_speed;
+                    self.speed_switch_armed = false;
                 }
                 else {
-                    f = f & 0b11101111;
+                    self.stopped = true;
+                    self.mmu.enter_stop();
                 }
+
+                length = 2;
+            },
+            0x18 => {//jr e8
+                let offset = self.read_from_memory(current_pc.wrapping_add(1)) as i8;
+                next_pc = current_pc.wrapping_add(2).wrapping_add(offset as u16);
+                jumped = true;
+
+                duration = 3;
             },
             0x20 | 0x28 | 0x30 | 0x38 => {//jr cc,e8
-                let cond = (self.opcode & 0b00011000) >> 3;
-                let jump: bool = match cond {
-                    0 => {
-                        (f & 0b10000000) == 0
-                    },
-                    1 => {
-                        (f & 0b10000000) != 0
-                    },
-                    2 => {
-                        (f & 0b00010000) == 0
-                    },
-                    3 => {
-                        (f & 0b00010000) != 0
-                    },
-                    _ => {
-                        false
-                    }
-                };
                 duration = 2;
-                if jump {
+                if check_condition((self.opcode & 0b00011000) >> 3, f) {
+                    let offset = self.read_from_memory(current_pc.wrapping_add(1)) as i8;
+                    next_pc = current_pc.wrapping_add(2).wrapping_add(offset as u16);
+                    jumped = true;
                     duration = 3;
-                    next_pc = current_pc + self.read_from_memory(current_pc.wrapping_add(1)) as u16;
                 }
                 length = 2;
-            }
+            },
+            0x27 => {//daa
+                let a = self.registers.read(RegisterName::A) as u8;
+                let (result, flags) = decimal_adjust_u8(a, f);
+                self.registers.write(RegisterName::A, result as u16);
+                f = flags;
+            },
+            0x2F => {//cpl
+                let a = self.registers.read(RegisterName::A) as u8;
+                self.registers.write(RegisterName::A, !a as u16);
+
+                f |= SUB_FLAG | HALF_CARRY_FLAG;
+            },
+            0x37 => {//scf
+                f = (f & ZERO_FLAG) | CARRY_FLAG;
+            },
+            0x3F => {//ccf
+                f = (f & (ZERO_FLAG | CARRY_FLAG)) ^ CARRY_FLAG;
+            },
             0x40..=0x7F => {//ld r8, r8
                 if self.opcode == 0x76 {//halt
-                    loop {
-                        
+                    if !self.ime && self.mmu.interrupts.pending() {
+                        self.halt_bug = true; //the cpu fails to increment pc after the next fetch
+                    }
+                    else {
+                        self.halted = true;
                     }
-                }
-                
-                let op1 = self.opcode & 0b00000111;
-                let mut src: u8 = 0;
-                if op1 == 0b0110 {
-                    src = self.read_from_memory(self.registers.read(RegisterName::HL));
-                    duration = 2;
                 }
                 else {
-                    src = self.registers.read(match_register_u8(op1)) as u8;
+                    let op1 = self.opcode & 0b00000111;
+                    let op2 = (self.opcode & 0b00111000) >> 3;
+                    let src = self.read_operand_u8(op1);
+                    self.write_operand_u8(op2, src);
+
+                    if op1 == 0b0110 || op2 == 0b0110 {
+                        duration = 2;
+                    }
                 }
-                
-                let op2 = (self.opcode & 0b00111000) >> 3;
-                if op2 == 0b0110 {
-                    self.write_to_memory(self.registers.read(RegisterName::HL), src);
+            },
+            0x80..=0xBF => {//add, adc, sub, sbc, and, xor, or, cp a, r8
+                let op = self.opcode & 0b00000111;
+                let r8 = self.read_operand_u8(op);
+                f = self.alu_u8((self.opcode & 0b00111000) >> 3, r8, f);
+
+                if op == 0b0110 {
                     duration = 2;
                 }
-                else {
-                    self.registers.write(match_register_u8(op2), src as u16);
+            },
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {//ret cc
+                duration = 2;
+                if check_condition((self.opcode & 0b00011000) >> 3, f) {
+                    next_pc = self.pop_u16();
+                    jumped = true;
+                    duration = 5;
+                }
+            },
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => {//pop r16
+                let register = match_register_u16_stack((self.opcode & 0b00110000) >> 4);
+                let data = self.pop_u16();
+                self.registers.write(register, data);
+                if self.opcode == 0xF1 {
+                    f = data as u8 & 0xF0; //lower nibble of f is always zero
                 }
-            }
-            0xB8..=0xBE => {//cp a, r8
-                let register = match_register_u8(self.opcode & 0b00000111);
-                let a = self.registers.read(RegisterName::A) as u8;
-                let r8 = self.registers.read(register) as u8;
 
-                f = set_sub_flags_u8(a, r8, f);
+                duration = 3;
             },
-            0xBF => {//cp a, a
-                f = 0b11000000;
-            }
-            0xC3 => {//jmp imm16
-                next_pc = (self.read_from_memory(current_pc.wrapping_add(1)) as u16) + (self.read_from_memory(current_pc.wrapping_add(2)) as u16) << 8;
+            0xC2 | 0xCA | 0xD2 | 0xDA => {//jp cc, imm16
+                duration = 3;
+                if check_condition((self.opcode & 0b00011000) >> 3, f) {
+                    next_pc = self.read_imm16(current_pc);
+                    jumped = true;
+                    duration = 4;
+                }
                 length = 3;
+            },
+            0xC3 => {//jmp imm16
+                next_pc = self.read_imm16(current_pc);
+                jumped = true;
+                duration = 4;
+            },
+            0xC4 | 0xCC | 0xD4 | 0xDC => {//call cc, imm16
                 duration = 3;
+                if check_condition((self.opcode & 0b00011000) >> 3, f) {
+                    next_pc = self.read_imm16(current_pc);
+                    jumped = true;
+                    self.push_u16(current_pc.wrapping_add(3));
+                    duration = 6;
+                }
+                length = 3;
             },
-            0xC6 => {//add a, imm8
-                let a = self.registers.read(RegisterName::A) as u8;
-                let imm8 = self.read_from_memory(current_pc.wrapping_add(1));
-                self.registers.write(RegisterName::A, (a.wrapping_add(imm8)) as u16);
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => {//push r16
+                let register = match_register_u16_stack((self.opcode & 0b00110000) >> 4);
+                let data = self.registers.read(register);
+                self.push_u16(data);
 
-                f = set_add_flags_u8(a, imm8, f);
+                duration = 4;
+            },
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {//add, adc, sub, sbc, and, xor, or, cp a, imm8
+                let imm8 = self.read_from_memory(current_pc.wrapping_add(1));
+                f = self.alu_u8((self.opcode & 0b00111000) >> 3, imm8, f);
 
                 length = 2;
                 duration = 2;
-            }
+            },
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {//rst vec
+                self.push_u16(current_pc.wrapping_add(1));
+                next_pc = (self.opcode & 0b00111000) as u16;
+                jumped = true;
+
+                duration = 4;
+            },
+            0xC9 => {//ret
+                next_pc = self.pop_u16();
+                jumped = true;
+
+                duration = 4;
+            },
+            0xCB => {//prefix
+                let cb_opcode = self.read_from_memory(current_pc.wrapping_add(1));
+                let (flags, cb_duration) = self.exec_cb(cb_opcode, f);
+                f = flags;
+
+                length = 2;
+                duration = cb_duration;
+            },
+            0xCD => {//call imm16
+                next_pc = self.read_imm16(current_pc);
+                jumped = true;
+                self.push_u16(current_pc.wrapping_add(3));
+
+                duration = 6;
+            },
+            0xD9 => {//reti
+                next_pc = self.pop_u16();
+                jumped = true;
+                self.ime = true;
+
+                duration = 4;
+            },
             0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
                 panic!("Invalid opcode!!! {:#04X?} at ${:04X?}", self.opcode, current_pc);
-            }
-            0xFA => {
-                                    
-            }
-            _ => {
-                panic!("Unimplemented opcode!!! {:#04X?} at ${:04X?}", self.opcode, current_pc);
+            },
+            0xE0 => {//ldh [imm8], a
+                let address = 0xFF00 | self.read_from_memory(current_pc.wrapping_add(1)) as u16;
+                let a = self.registers.read(RegisterName::A) as u8;
+                self.write_to_memory(address, a);
+
+                length = 2;
+                duration = 3;
+            },
+            0xF0 => {//ldh a, [imm8]
+                let address = 0xFF00 | self.read_from_memory(current_pc.wrapping_add(1)) as u16;
+                let data = self.read_from_memory(address);
+                self.registers.write(RegisterName::A, data as u16);
+
+                length = 2;
+                duration = 3;
+            },
+            0xE2 => {//ldh [c], a
+                let address = 0xFF00 | self.registers.read(RegisterName::C);
+                let a = self.registers.read(RegisterName::A) as u8;
+                self.write_to_memory(address, a);
+
+                duration = 2;
+            },
+            0xF2 => {//ldh a, [c]
+                let address = 0xFF00 | self.registers.read(RegisterName::C);
+                let data = self.read_from_memory(address);
+                self.registers.write(RegisterName::A, data as u16);
+
+                duration = 2;
+            },
+            0xE8 => {//add sp, e8
+                let sp = self.registers.read(RegisterName::SP);
+                let e8 = self.read_from_memory(current_pc.wrapping_add(1));
+                self.registers.write(RegisterName::SP, sp.wrapping_add(e8 as i8 as u16));
+
+                f = set_add_flags_u8(sp as u8, e8, f) & (HALF_CARRY_FLAG | CARRY_FLAG);
+
+                length = 2;
+                duration = 4;
+            },
+            0xF8 => {//ld hl, sp + e8
+                let sp = self.registers.read(RegisterName::SP);
+                let e8 = self.read_from_memory(current_pc.wrapping_add(1));
+                self.registers.write(RegisterName::HL, sp.wrapping_add(e8 as i8 as u16));
+
+                f = set_add_flags_u8(sp as u8, e8, f) & (HALF_CARRY_FLAG | CARRY_FLAG);
+
+                length = 2;
+                duration = 3;
+            },
+            0xE9 => {//jp hl
+                next_pc = self.registers.read(RegisterName::HL);
+                jumped = true;
+            },
+            0xF9 => {//ld sp, hl
+                let hl = self.registers.read(RegisterName::HL);
+                self.registers.write(RegisterName::SP, hl);
+
+                duration = 2;
+            },
+            0xF3 => {//di
+                self.ime = false;
+                self.ime_scheduled = false;
+            },
+            0xFB => {//ei
+                self.ime_scheduled = true;
+            },
+            0xEA => {//ld [imm16], a
+                let address = self.read_imm16(current_pc);
+                let a = self.registers.read(RegisterName::A) as u8;
+                self.write_to_memory(address, a);
+
+                length = 3;
+                duration = 4;
+            },
+            0xFA => {//ld a, [imm16]
+                let address = self.read_imm16(current_pc);
+                let data = self.read_from_memory(address);
+                self.registers.write(RegisterName::A, data as u16);
+
+                length = 3;
+                duration = 4;
             }
         }
 
         self.registers.write(RegisterName::F, f as u16);
         
-        if next_pc == current_pc {
-            next_pc = next_pc.wrapping_add(length);
+        if !jumped {
+            next_pc = current_pc.wrapping_add(length);
         }
         self.registers.write(RegisterName::PC, next_pc);
         self.opcode = self.read_from_memory(next_pc);
+        if self.halt_bug {
+            self.registers.write(RegisterName::PC, next_pc.wrapping_sub(1));
+            self.halt_bug = false;
+        }
         duration
     }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 8];
+        reader.bytes(&mut magic)?;
+        if magic != STATE_MAGIC {
+            return Err(state::invalid_state("Not a save state"));
+        }
+        if reader.u32()? != STATE_VERSION {
+            return Err(state::invalid_state("Save state is from an incompatible version"));
+        }
+        let mut rom_id = [0; 3];
+        reader.bytes(&mut rom_id)?;
+        if rom_id != self.mmu.rom().data[ROM_ID] {
+            return Err(state::invalid_state("Save state is for a different game"));
+        }
+        if data.len() != self.state_size {
+            return Err(state::invalid_state("Save state has the wrong size"));
+        }
+
+        self.registers.load_state(&mut reader)?;
+        self.opcode = reader.u8()?;
+        for flag in [&mut self.ime, &mut self.ime_scheduled, &mut self.halted, &mut self.halt_bug,
+                     &mut self.stopped, &mut self.double_speed, &mut self.speed_switch_armed] {
+            *flag = reader.bool()?;
+        }
+        self.mmu.load_state(&mut reader)
+    }
+    fn dispatch_interrupt(&mut self, interrupt: Interrupt) -> i32 {//the prefetched opcode is discarded and pc pushed
+        self.ime = false;
+        self.mmu.interrupts.acknowledge(interrupt);
+
+        let pc = self.registers.read(RegisterName::PC);
+        self.push_u16(pc);
+        self.registers.write(RegisterName::PC, interrupt.vector());
+        self.opcode = self.read_from_memory(interrupt.vector());
+        5
+    }
+
+    fn exec_cb(&mut self, cb_opcode: u8, f: u8) -> (u8, i32) {//returns new flags and number of m-cycles
+        let op = cb_opcode & 0b00000111;
+        let bit = (cb_opcode & 0b00111000) >> 3;
+        let value = self.read_operand_u8(op);
+        let mut flags = f;
+
+        match cb_opcode {
+            0x00..=0x3F => {//rlc, rrc, rl, rr, sla, sra, swap, srl r8
+                let (result, shift_flags) = rotate_shift_u8(bit, value, f);
+                self.write_operand_u8(op, result);
+                flags = shift_flags;
+            },
+            0x40..=0x7F => {//bit u3, r8
+                flags = (f & CARRY_FLAG) | HALF_CARRY_FLAG;
+                if value & (1 << bit) == 0 {
+                    flags |= ZERO_FLAG;
+                }
+                if op == 0b0110 {
+                    return (flags, 3); //bit doesn't write back to [hl]
+                }
+            },
+            0x80..=0xBF => {//res u3, r8
+                self.write_operand_u8(op, value & !(1 << bit));
+            },
+            0xC0..=0xFF => {//set u3, r8
+                self.write_operand_u8(op, value | (1 << bit));
+            }
+        }
+
+        if op == 0b0110 {
+            return (flags, 4);
+        }
+        (flags, 2)
+    }
+
+    fn read_imm16(&mut self, current_pc: u16) -> u16 {//little-endian operand following the opcode
+        let low = self.read_from_memory(current_pc.wrapping_add(1)) as u16;
+        let high = self.read_from_memory(current_pc.wrapping_add(2)) as u16;
+        (high << 8) | low
+    }
+
+    fn indirect_address(&mut self, op: u8) -> u16 {//decodes [bc], [de], [hl+], [hl-]
+        match op {
+            0 => self.registers.read(RegisterName::BC),
+            1 => self.registers.read(RegisterName::DE),
+            2 => {
+                let hl = self.registers.read(RegisterName::HL);
+                self.registers.write(RegisterName::HL, hl.wrapping_add(1));
+                hl
+            },
+            3 => {
+                let hl = self.registers.read(RegisterName::HL);
+                self.registers.write(RegisterName::HL, hl.wrapping_sub(1));
+                hl
+            },
+            _ => panic!("Invalid indirect register!!! {}", op)
+        }
+    }
+
+    fn read_operand_u8(&mut self, op: u8) -> u8 {//like match_register_u8, but 6 is [hl]
+        if op == 0b0110 {
+            let hl = self.registers.read(RegisterName::HL);
+            return self.read_from_memory(hl);
+        }
+        self.registers.read(match_register_u8(op)) as u8
+    }
+
+    fn write_operand_u8(&mut self, op: u8, data: u8) {
+        if op == 0b0110 {
+            let hl = self.registers.read(RegisterName::HL);
+            self.write_to_memory(hl, data);
+            return;
+        }
+        self.registers.write(match_register_u8(op), data as u16);
+    }
+
+    fn alu_u8(&mut self, op: u8, value: u8, f: u8) -> u8 {//applies an 8-bit alu op to a, returns the new flags
+        let a = self.registers.read(RegisterName::A) as u8;
+        let carry = (f & CARRY_FLAG) >> 4;
+        let (result, flags) = match op {
+            0 => (a.wrapping_add(value), set_add_flags_u8(a, value, f)),
+            1 => (a.wrapping_add(value).wrapping_add(carry), set_adc_flags_u8(a, value, carry, f)),
+            2 => (a.wrapping_sub(value), set_sub_flags_u8(a, value, f)),
+            3 => (a.wrapping_sub(value).wrapping_sub(carry), set_sbc_flags_u8(a, value, carry, f)),
+            4 => (a & value, HALF_CARRY_FLAG),
+            5 => (a ^ value, 0),
+            6 => (a | value, 0),
+            7 => (a, set_sub_flags_u8(a, value, f)),
+            _ => panic!("Invalid alu op!!! {}", op)
+        };
+        if op != 7 {
+            self.registers.write(RegisterName::A, result as u16);
+        }
+        if op >= 4 && op != 7 && result == 0 {
+            return flags | ZERO_FLAG;
+        }
+        flags
+    }
+
+    fn push_u16(&mut self, data: u16) {
+        let sp = self.registers.read(RegisterName::SP);
+        self.write_to_memory(sp.wrapping_sub(1), (data >> 8) as u8);
+        self.write_to_memory(sp.wrapping_sub(2), data as u8);
+        self.registers.write(RegisterName::SP, sp.wrapping_sub(2));
+    }
+
+    fn pop_u16(&mut self) -> u16 {
+        let sp = self.registers.read(RegisterName::SP);
+        let low = self.read_from_memory(sp) as u16;
+        let high = self.read_from_memory(sp.wrapping_add(1)) as u16;
+        self.registers.write(RegisterName::SP, sp.wrapping_add(2));
+        (high << 8) | low
+    }
     
     fn read_from_memory(&mut self, address: u16) -> u8 {
         match address {
-            0x0000..=0x3FFF => {
-                match self.rom.cartridge_type {
-                    0x13 => {
-                        return self.rom.data[address as usize];
-                    }
-                    _=> {
-                        panic!("Unimplemented MBC!!! {:#04X?}", self.rom.cartridge_type)
-                    }
-                }
-            }
-            0x4000..0x7FFF => {
-                match self.rom.cartridge_type {
-                    0x13 => {
-                        return self.rom.data[(address.wrapping_add(0x4000 * (self.mbc.active_bank - 1))) as usize];
-                    }
-                    _=> {
-                        panic!("Unimplemented MBC!!! {:#04X?}", self.rom.cartridge_type)
-                    }
+            0xFF4D => {
+                if !self.mmu.rom().is_cgb() {
+                    return 0xFF;
                 }
+                ((self.double_speed as u8) << 7) | 0b01111110 | self.speed_switch_armed as u8
             }
             _ => {
-                panic!("Unimplemented read!!! ${:04X?}", address);
+                self.mmu.read(address)
             }
         }
     }
     
     fn write_to_memory(&mut self, address: u16, data: u8) {
-        panic!("Unimplemented write!!! ${:04X?}", address);
-    }    
+        match address {
+            0xFF4D => {
+                self.speed_switch_armed = data & 1 != 0;
+            }
+            _ => {
+                self.mmu.write(address, data);
+            }
+        }
+    }
 
 }
 
@@ -221,42 +717,224 @@ fn match_register_u8(op: u8) -> RegisterName {
     }
 }
 
+fn match_register_u16(op: u8) -> RegisterName {
+    match op {
+        0 => RegisterName::BC,
+        1 => RegisterName::DE,
+        2 => RegisterName::HL,
+        3 => RegisterName::SP,
+        _ => panic!("Invalid register!!! {}", op)
+    }
+}
+
+fn match_register_u16_stack(op: u8) -> RegisterName {//push and pop use af in place of sp
+    match op {
+        3 => RegisterName::AF,
+        _ => match_register_u16(op)
+    }
+}
+
+fn check_condition(cond: u8, f: u8) -> bool {//nz, z, nc, c
+    match cond {
+        0 => f & ZERO_FLAG == 0,
+        1 => f & ZERO_FLAG != 0,
+        2 => f & CARRY_FLAG == 0,
+        3 => f & CARRY_FLAG != 0,
+        _ => panic!("Invalid condition!!! {}", cond)
+    }
+}
+
+fn rotate_shift_u8(op: u8, value: u8, f: u8) -> (u8, u8) {//rlc, rrc, rl, rr, sla, sra, swap, srl; returns result and flags
+    let carry_in = (f & CARRY_FLAG) >> 4;
+    let (result, carry_out) = match op {
+        0 => (value.rotate_left(1), value >> 7),
+        1 => (value.rotate_right(1), value & 1),
+        2 => ((value << 1) | carry_in, value >> 7),
+        3 => ((value >> 1) | (carry_in << 7), value & 1),
+        4 => (value << 1, value >> 7),
+        5 => ((value >> 1) | (value & 0x80), value & 1),
+        6 => (value.rotate_left(4), 0),
+        7 => (value >> 1, value & 1),
+        _ => panic!("Invalid rotate!!! {}", op)
+    };
+    let mut flags = 0;
+    if result == 0 {
+        flags |= ZERO_FLAG;
+    }
+    if carry_out != 0 {
+        flags |= CARRY_FLAG;
+    }
+    (result, flags)
+}
+
+fn decimal_adjust_u8(a: u8, f: u8) -> (u8, u8) {//daa, corrects a after a bcd add or subtract; returns result and flags
+    let mut a = a;
+    let mut carry = f & CARRY_FLAG != 0;
+    if f & SUB_FLAG == 0 {
+        if carry || a > 0x99 {
+            a = a.wrapping_add(0x60);
+            carry = true;
+        }
+        if f & HALF_CARRY_FLAG != 0 || (a & 0x0F) > 0x09 {
+            a = a.wrapping_add(0x06);
+        }
+    }
+    else {
+        if carry {
+            a = a.wrapping_sub(0x60);
+        }
+        if f & HALF_CARRY_FLAG != 0 {
+            a = a.wrapping_sub(0x06);
+        }
+    }
+
+    let mut flags = f & SUB_FLAG;
+    if a == 0 {
+        flags |= ZERO_FLAG;
+    }
+    if carry {
+        flags |= CARRY_FLAG;
+    }
+    (a, flags)
+}
+
 fn set_add_flags_u8(op1: u8, op2: u8, f: u8) -> u8 {
-    let mut flags = f;
+    set_adc_flags_u8(op1, op2, 0, f)
+}
+
+fn set_adc_flags_u8(op1: u8, op2: u8, carry: u8, f: u8) -> u8 {
+    let mut flags = f & !(ZERO_FLAG | SUB_FLAG | HALF_CARRY_FLAG | CARRY_FLAG);
     
-    if op1.wrapping_add(op2) == 0 {
-        flags = flags | ZERO_FLAG;
+    if op1.wrapping_add(op2).wrapping_add(carry) == 0 {
+        flags |= ZERO_FLAG;
     }
     
-    flags = flags & !SUB_FLAG;
-    
-    if (op1 & 0x0F).wrapping_add(op2 & 0x0F) & 0x10 == 0x10 {
-        flags = flags | HALF_CARRY_FLAG;
+    if (op1 & 0x0F) + (op2 & 0x0F) + carry > 0x0F {
+        flags |= HALF_CARRY_FLAG;
     }
     
-    if op1 as u16 + op2 as u16 > 0xFF {
-        flags = flags | CARRY_FLAG;
+    if op1 as u16 + op2 as u16 + carry as u16 > 0xFF {
+        flags |= CARRY_FLAG;
     }
     
     flags
 }
 
 fn set_sub_flags_u8(op1: u8, op2: u8, f: u8) -> u8 {
-    let mut flags = f;
+    set_sbc_flags_u8(op1, op2, 0, f)
+}
+
+fn set_sbc_flags_u8(op1: u8, op2: u8, carry: u8, f: u8) -> u8 {
+    let mut flags = (f & !(ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG)) | SUB_FLAG;
     
-    if op1.wrapping_sub(op2) == 0 {
-        flags = flags | ZERO_FLAG;
+    if op1.wrapping_sub(op2).wrapping_sub(carry) == 0 {
+        flags |= ZERO_FLAG;
     }
     
-    flags = flags | SUB_FLAG;
-    
-    if (op1 & 0x0F).wrapping_sub(op2 & 0x0F) & 0x10 == 0x10 {
-        flags = flags | HALF_CARRY_FLAG;
+    if (op1 & 0x0F) < (op2 & 0x0F) + carry {
+        flags |= HALF_CARRY_FLAG;
     }
     
-    if op1 < op2 {
-        flags = flags | CARRY_FLAG;
+    if (op1 as u16) < op2 as u16 + carry as u16 {
+        flags |= CARRY_FLAG;
     }
     
     flags
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_sets_half_carry_and_carry() {
+        assert_eq!(set_add_flags_u8(0x0F, 0x01, 0), HALF_CARRY_FLAG);
+        assert_eq!(set_add_flags_u8(0xF0, 0x10, 0), ZERO_FLAG | CARRY_FLAG);
+        assert_eq!(set_add_flags_u8(0xFF, 0x01, 0), ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG);
+        assert_eq!(set_add_flags_u8(0x12, 0x34, SUB_FLAG), 0);
+    }
+
+    #[test]
+    fn adc_includes_carry_in_both_carries() {
+        assert_eq!(set_adc_flags_u8(0x0E, 0x01, 1, 0), HALF_CARRY_FLAG);
+        assert_eq!(set_adc_flags_u8(0xFE, 0x01, 1, 0), ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG);
+        assert_eq!(set_adc_flags_u8(0x0E, 0x01, 0, 0), 0);
+    }
+
+    #[test]
+    fn sub_sets_borrows() {
+        assert_eq!(set_sub_flags_u8(0x10, 0x01, 0), SUB_FLAG | HALF_CARRY_FLAG);
+        assert_eq!(set_sub_flags_u8(0x00, 0x10, 0), SUB_FLAG | CARRY_FLAG);
+        assert_eq!(set_sub_flags_u8(0x42, 0x42, 0), SUB_FLAG | ZERO_FLAG);
+    }
+
+    #[test]
+    fn sbc_includes_carry_in_both_borrows() {
+        assert_eq!(set_sbc_flags_u8(0x10, 0x00, 1, 0), SUB_FLAG | HALF_CARRY_FLAG);
+        assert_eq!(set_sbc_flags_u8(0x00, 0xFF, 1, 0), SUB_FLAG | ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG);
+        assert_eq!(set_sbc_flags_u8(0x01, 0x00, 1, 0), SUB_FLAG | ZERO_FLAG);
+    }
+
+    #[test]
+    fn daa_matches_bcd_arithmetic() {//every pair of bcd digits, added and subtracted, against the decimal result
+        for x in 0..100u8 {
+            for y in 0..100u8 {
+                let bx = (x / 10) << 4 | (x % 10);
+                let by = (y / 10) << 4 | (y % 10);
+
+                let sum = bx.wrapping_add(by);
+                let (result, flags) = decimal_adjust_u8(sum, set_add_flags_u8(bx, by, 0));
+                let expected = (x as u16 + y as u16) % 100;
+                assert_eq!(result, ((expected / 10) << 4 | (expected % 10)) as u8, "{} + {}", x, y);
+                assert_eq!(flags & CARRY_FLAG != 0, x as u16 + y as u16 >= 100, "{} + {}", x, y);
+                assert_eq!(flags & ZERO_FLAG != 0, expected == 0, "{} + {}", x, y);
+
+                let difference = bx.wrapping_sub(by);
+                let (result, flags) = decimal_adjust_u8(difference, set_sub_flags_u8(bx, by, 0));
+                let expected = (x as i16 - y as i16).rem_euclid(100) as u8;
+                assert_eq!(result, (expected / 10) << 4 | (expected % 10), "{} - {}", x, y);
+                assert_eq!(flags & CARRY_FLAG != 0, x < y, "{} - {}", x, y);
+                assert_eq!(flags & SUB_FLAG, SUB_FLAG);
+            }
+        }
+    }
+
+    #[test]
+    fn daa_clears_half_carry() {
+        let (result, flags) = decimal_adjust_u8(0x0A, HALF_CARRY_FLAG);
+        assert_eq!(result, 0x10);
+        assert_eq!(flags & HALF_CARRY_FLAG, 0);
+    }
+
+    #[test]
+    fn corrupt_state_is_rejected_and_rolled_back() {//right size and header, but every field after it out of range
+        let mut rom = Rom::default();
+        rom.data = vec![0; 0x8000];
+        let mut cpu = Cpu::new(rom);
+        for _ in 0..100000 {
+            cpu.exec();
+        }
+        let before = cpu.save_state();
+        cpu.load_state(&before).unwrap();
+        let mut corrupt = before.clone();
+        let header = STATE_MAGIC.len() + 4 + ROM_ID.len();
+        corrupt[header..].fill(0xFF);
+
+        let error = cpu.load_state(&corrupt).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn loaded_flags_keep_the_low_nibble_clear() {
+        let mut rom = Rom::default();
+        rom.data = vec![0; 0x8000];
+        let mut cpu = Cpu::new(rom);
+        let mut data = cpu.save_state();
+        let header = STATE_MAGIC.len() + 4 + ROM_ID.len();
+        data[header] = 0xFF; //af is saved first, low byte first
+
+        cpu.load_state(&data).unwrap();
+        assert_eq!(cpu.registers.read(RegisterName::F), 0xF0);
+    }
+}
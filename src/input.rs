@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{Axis, GameController};
+use sdl2::controller::Button as ControllerButton;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+
+use crate::joypad::Button;
+
+const DEFAULT_DEADZONE: i16 = 8000;
+const RUMBLE_STRENGTH: u16 = 0xC000;
+const RUMBLE_DURATION: u32 = 10_000; //ms, rumble stays on until the cart turns it off
+const STATE_SLOTS: u8 = 10;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Hotkey {//emulator controls, bound like joypad buttons
+    Slower,
+    Faster,
+    ToggleLimiter,
+    Pause,
+    FrameAdvance,
+    Rewind,
+    SaveState(u8), //slots 1-10
+    LoadState(u8),
+}
+
+impl Hotkey {
+    fn all() -> Vec<Hotkey> {
+        let mut hotkeys = vec![Hotkey::Slower, Hotkey::Faster, Hotkey::ToggleLimiter, Hotkey::Pause, Hotkey::FrameAdvance, Hotkey::Rewind];
+        hotkeys.extend((1..=STATE_SLOTS).map(Hotkey::SaveState));
+        hotkeys.extend((1..=STATE_SLOTS).map(Hotkey::LoadState));
+        hotkeys
+    }
+    fn name(self) -> String {//as written in a bindings file
+        match self {
+            Hotkey::Slower => String::from("slower"),
+            Hotkey::Faster => String::from("faster"),
+            Hotkey::ToggleLimiter => String::from("limiter"),
+            Hotkey::Pause => String::from("pause"),
+            Hotkey::FrameAdvance => String::from("frame_advance"),
+            Hotkey::Rewind => String::from("rewind"),
+            Hotkey::SaveState(slot) => format!("save_state_{}", slot),
+            Hotkey::LoadState(slot) => format!("load_state_{}", slot),
+        }
+    }
+    fn default_binding(self) -> String {
+        match self {
+            Hotkey::Slower => String::from("key:-"),
+            Hotkey::Faster => String::from("key:="),
+            Hotkey::ToggleLimiter => String::from("key:Tab"),
+            Hotkey::Pause => String::from("key:P"),
+            Hotkey::FrameAdvance => String::from("key:N"),
+            Hotkey::Rewind => String::from("key:R"),
+            Hotkey::SaveState(slot) => format!("key:Shift+F{}", slot),
+            Hotkey::LoadState(slot) => format!("key:F{}", slot),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Action {
+    Button(Button),
+    Hotkey(Hotkey),
+}
+
+impl Action {
+    fn name(self) -> String {
+        match self {
+            Action::Button(button) => format!("{:?}", button).to_lowercase(),
+            Action::Hotkey(hotkey) => hotkey.name(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum Binding {
+    Key(Keycode, bool), //true when shift has to be held
+    ControllerButton(ControllerButton),
+    Axis(Axis, bool), //true for the positive direction
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum Source {//a binding that is currently held, controllers are told apart by instance id
+    Key(Keycode),
+    ControllerButton(u32, ControllerButton),
+    Axis(u32, Axis, bool),
+}
+
+pub struct Bindings {
+    bindings: Vec<(Binding, Action)>, //several bindings may map to the same action
+    deadzone: i16,
+}
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        let mut bindings = Bindings {bindings: Vec::new(), deadzone: DEFAULT_DEADZONE};
+        bindings.parse("
+            right = key:Right, controller:dpright, axis:leftx+
+            left = key:Left, controller:dpleft, axis:leftx-
+            up = key:Up, controller:dpup, axis:lefty-
+            down = key:Down, controller:dpdown, axis:lefty+
+            a = key:X, controller:b
+            b = key:Z, controller:a
+            select = key:Backspace, controller:back
+            start = key:Return, controller:start
+        ").unwrap();
+        bindings.add_default_hotkeys();
+        bindings
+    }
+}
+
+impl Bindings {
+    pub fn load(path: &Path) -> Result<Bindings, Error> {//one "button = binding, binding" line per button, # starts a comment
+        let text = fs::read_to_string(path)?;
+        let mut bindings = Bindings {bindings: Vec::new(), deadzone: DEFAULT_DEADZONE};
+        bindings.parse(&text)
+            .and_then(|_| {
+                bindings.add_default_hotkeys();
+                bindings.check_conflicts()
+            })
+            .map_err(|message| Error::new(std::io::ErrorKind::InvalidData, message))?;
+        Ok(bindings)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<(), String> {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, values) = line.split_once('=').ok_or(format!("Missing '=' in binding: {}", line))?;
+            let name = name.trim();
+            if name == "deadzone" {
+                self.deadzone = values.trim().parse().ok()
+                    .filter(|deadzone| *deadzone >= 0) //negative values would hold both directions of an axis at once
+                    .ok_or(format!("Invalid deadzone: {}", values))?;
+                continue;
+            }
+            let action = match_action_name(name).ok_or(format!("Unknown button or hotkey: {}", name))?;
+            for value in values.split(',') {
+                let binding = parse_binding(value.trim()).ok_or(format!("Invalid binding: {}", value))?;
+                self.bindings.push((binding, action));
+            }
+        }
+        Ok(())
+    }
+    fn add_default_hotkeys(&mut self) {//hotkeys a bindings file leaves out keep their default keys
+        for hotkey in Hotkey::all() {
+            if self.bindings.iter().any(|(_, action)| *action == Action::Hotkey(hotkey)) {
+                continue;
+            }
+            let binding = parse_binding(&hotkey.default_binding()).unwrap();
+            self.bindings.push((binding, Action::Hotkey(hotkey)));
+        }
+    }
+    fn check_conflicts(&self) -> Result<(), String> {//a hotkey can't share its binding with anything else
+        for (binding, action) in &self.bindings {
+            if let Action::Hotkey(_) = action {
+                if let Some((_, other)) = self.bindings.iter().find(|(bound, other)| bound == binding && other != action) {
+                    return Err(format!("{:?} is bound to both {} and {}, rebind one of them", binding, action.name(), other.name()));
+                }
+            }
+        }
+        Ok(())
+    }
+    fn actions(&self, binding: Binding) -> impl Iterator<Item = Action> + '_ {
+        self.bindings.iter()
+            .filter(move |(bound, _)| *bound == binding)
+            .map(|(_, action)| *action)
+    }
+}
+
+pub struct Input {//turns sdl keyboard and controller events into joypad button and hotkey changes
+    bindings: Bindings,
+    subsystem: GameControllerSubsystem,
+    controllers: HashMap<u32, GameController>, //open controllers by instance id
+    held: HashSet<(Source, Action)>,
+}
+
+impl Input {
+    pub fn new(bindings: Bindings, subsystem: GameControllerSubsystem) -> Input {
+        Input {
+            bindings,
+            subsystem,
+            controllers: HashMap::new(),
+            held: HashSet::new(),
+        }
+    }
+    pub fn handle_event(&mut self, event: &Event) -> Vec<(Action, bool)> {//returns the actions that changed and their new state
+        match *event {
+            Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } => {
+                let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                let binding = if shift && self.bindings.actions(Binding::Key(keycode, true)).next().is_some() {
+                    Binding::Key(keycode, true)
+                }
+                else {//keys without a shifted binding act the same with shift held
+                    Binding::Key(keycode, false)
+                };
+                self.set_held(Source::Key(keycode), binding, true)
+            }
+            Event::KeyUp { keycode: Some(keycode), .. } => {//shift may have changed since the press, so release whatever the key started
+                self.release(|source| *source == Source::Key(keycode))
+            }
+            Event::ControllerButtonDown { which, button, .. } => {
+                self.set_held(Source::ControllerButton(which, button), Binding::ControllerButton(button), true)
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.set_held(Source::ControllerButton(which, button), Binding::ControllerButton(button), false)
+            }
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                let deadzone = self.bindings.deadzone;
+                let mut changes = self.set_held(Source::Axis(which, axis, true), Binding::Axis(axis, true), value > deadzone);
+                changes.extend(self.set_held(Source::Axis(which, axis, false), Binding::Axis(axis, false), value < -deadzone));
+                changes
+            }
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.subsystem.open(which) {
+                    Ok(controller) => {
+                        println!("Controller connected: {}", controller.name());
+                        self.controllers.insert(controller.instance_id(), controller);
+                    }
+                    Err(error) => println!("Failed to open controller {}: {}", which, error),
+                }
+                Vec::new()
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.remove(&which);
+                self.release(|source| match source {//a controller unplugged with buttons held
+                    Source::ControllerButton(id, _) | Source::Axis(id, _, _) => *id == which,
+                    Source::Key(_) => false,
+                })
+            }
+            _ => Vec::new()
+        }
+    }
+    pub fn set_rumble(&mut self, enabled: bool) {
+        let strength = if enabled { RUMBLE_STRENGTH } else { 0 };
+        for controller in self.controllers.values_mut() {
+            let _ = controller.set_rumble(strength, strength, RUMBLE_DURATION); //not every controller can rumble
+        }
+    }
+
+    fn set_held(&mut self, source: Source, binding: Binding, held: bool) -> Vec<(Action, bool)> {
+        let actions: Vec<Action> = self.bindings.actions(binding).collect();
+        let mut changes = Vec::new();
+        for action in actions {
+            let was_pressed = self.pressed(action);
+            if held {
+                self.held.insert((source, action));
+            }
+            else {
+                self.held.remove(&(source, action));
+            }
+            if self.pressed(action) != was_pressed {
+                changes.push((action, !was_pressed));
+            }
+        }
+        changes
+    }
+    fn release(&mut self, released: impl Fn(&Source) -> bool) -> Vec<(Action, bool)> {//drops every held entry from the matching sources
+        let sources: Vec<(Source, Action)> = self.held.iter()
+            .filter(|(source, _)| released(source))
+            .cloned()
+            .collect();
+        let mut changes = Vec::new();
+        for (source, action) in sources {
+            self.held.remove(&(source, action));
+            if !self.pressed(action) {
+                changes.push((action, false));
+            }
+        }
+        changes
+    }
+    fn pressed(&self, action: Action) -> bool {
+        self.held.iter().any(|(_, held)| *held == action)
+    }
+}
+
+fn match_action_name(name: &str) -> Option<Action> {
+    let buttons = Button::ALL.into_iter().map(Action::Button);
+    let hotkeys = Hotkey::all().into_iter().map(Action::Hotkey);
+    buttons.chain(hotkeys).find(|action| action.name().eq_ignore_ascii_case(name))
+}
+
+fn parse_binding(value: &str) -> Option<Binding> {//key:[Shift+]<sdl key name>, controller:<sdl button name> or axis:<sdl axis name>+/-
+    let (kind, name) = value.split_once(':')?;
+    match kind.trim() {
+        "key" => {
+            let name = name.trim();
+            match name.strip_prefix("Shift+") {
+                Some(name) => Keycode::from_name(name).map(|keycode| Binding::Key(keycode, true)),
+                None => Keycode::from_name(name).map(|keycode| Binding::Key(keycode, false)),
+            }
+        }
+        "controller" => ControllerButton::from_string(name.trim()).map(Binding::ControllerButton),
+        "axis" => {
+            let name = name.trim();
+            let positive = name.ends_with('+');
+            if !positive && !name.ends_with('-') {
+                return None;
+            }
+            Axis::from_string(&name[..name.len() - 1]).map(|axis| Binding::Axis(axis, positive))
+        }
+        _ => None
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::Error;
+
+use crate::state::{StateReader, StateWriter};
+
+const INTERRUPT_MASK: u8 = 0b00011111;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {//listed in priority order
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad
+}
+
+impl Interrupt {
+    const ALL: [Interrupt; 5] = [Interrupt::VBlank, Interrupt::Stat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad];
+
+    pub fn bit(self) -> u8 {
+        match self {
+            Interrupt::VBlank => 0b00000001,
+            Interrupt::Stat => 0b00000010,
+            Interrupt::Timer => 0b00000100,
+            Interrupt::Serial => 0b00001000,
+            Interrupt::Joypad => 0b00010000,
+        }
+    }
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x0040,
+            Interrupt::Stat => 0x0048,
+            Interrupt::Timer => 0x0050,
+            Interrupt::Serial => 0x0058,
+            Interrupt::Joypad => 0x0060,
+        }
+    }
+}
+
+pub struct Interrupts {
+    pub enable: u8, //$FFFF
+    pub flags: u8, //$FF0F
+}
+
+impl Interrupts {
+    pub fn new() -> Interrupts {
+        Interrupts {//emulates behavior of DMG after the boot rom
+            enable: 0x00,
+            flags: 0x01,
+        }
+    }
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.flags |= interrupt.bit();
+    }
+    pub fn acknowledge(&mut self, interrupt: Interrupt) {
+        self.flags &= !interrupt.bit();
+    }
+    pub fn pending(&self) -> bool {
+        self.enable & self.flags & INTERRUPT_MASK != 0
+    }
+    pub fn highest_pending(&self) -> Option<Interrupt> {
+        let pending = self.enable & self.flags & INTERRUPT_MASK;
+        Interrupt::ALL.into_iter().find(|interrupt| pending & interrupt.bit() != 0)
+    }
+    pub fn read_flags(&self) -> u8 {
+        self.flags | !INTERRUPT_MASK //unused bits read as 1
+    }
+    pub fn write_flags(&mut self, data: u8) {
+        self.flags = data & INTERRUPT_MASK;
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.enable);
+        writer.u8(self.flags);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.enable = reader.u8()?;
+        self.flags = reader.u8()? & INTERRUPT_MASK;
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::Error;
+
+use crate::state::{StateReader, StateWriter};
+use crate::interrupts::{Interrupt, Interrupts};
+
+const SELECT_DIRECTIONS: u8 = 0b00010000; //active low
+const SELECT_ACTIONS: u8 = 0b00100000; //active low
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [Button::Right, Button::Left, Button::Up, Button::Down, Button::A, Button::B, Button::Select, Button::Start];
+
+    fn bit(self) -> u8 {//directions in the low nibble, actions in the high nibble
+        match self {
+            Button::Right => 0b00000001,
+            Button::Left => 0b00000010,
+            Button::Up => 0b00000100,
+            Button::Down => 0b00001000,
+            Button::A => 0b00010000,
+            Button::B => 0b00100000,
+            Button::Select => 0b01000000,
+            Button::Start => 0b10000000,
+        }
+    }
+}
+
+pub struct Joypad {//$FF00
+    select: u8, //bits 4 and 5 as written by the game
+    pressed: u8, //one bit per button, 1 is pressed
+    press_latched: bool, //a button went down since stop was entered, which wakes the cpu
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0b00110000,
+            pressed: 0,
+            press_latched: false,
+        }
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {//held buttons come from the host, so only the selection is saved
+        writer.u8(self.select);
+        writer.bool(self.press_latched);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.select = reader.u8()? & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.press_latched = reader.bool()?;
+        Ok(())
+    }
+    pub fn read(&self) -> u8 {
+        0b11000000 | self.select | (!self.lines() & 0x0F)
+    }
+    pub fn write(&mut self, data: u8, interrupts: &mut Interrupts) {
+        let old_lines = self.lines();
+        self.select = data & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.check_interrupt(old_lines, interrupts);
+    }
+    pub fn press(&mut self, button: Button, interrupts: &mut Interrupts) {
+        let old_lines = self.lines();
+        if self.pressed & button.bit() == 0 {
+            self.press_latched = true;
+        }
+        self.pressed |= button.bit();
+        self.check_interrupt(old_lines, interrupts);
+    }
+    pub fn press_latched(&self) -> bool {
+        self.press_latched
+    }
+    pub fn clear_press_latch(&mut self) {
+        self.press_latched = false;
+    }
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.bit();
+    }
+
+    fn lines(&self) -> u8 {//p10-p13 with 1 meaning pulled low by a selected, pressed button
+        let mut lines = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            lines |= self.pressed >> 4;
+        }
+        lines
+    }
+    fn check_interrupt(&self, old_lines: u8, interrupts: &mut Interrupts) {//fires on any high-to-low transition
+        if self.lines() & !old_lines != 0 {
+            interrupts.request(Interrupt::Joypad);
+        }
+    }
+}
@@ -5,31 +5,52 @@ mod registers;
 mod render;
 mod screen;
 mod cpu;
+mod interrupts;
+mod mbc;
+mod mmu;
+mod ppu;
+mod rtc;
+mod timer;
+mod apu;
+mod joypad;
+mod input;
+mod audio;
+mod speed;
+mod state;
+mod rewind;
 
 extern crate sdl2;
 extern crate spin_sleep;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 
-use render::Renderer;
+use render::{Palette, Renderer};
 use registers::Registers;
 use rom::Rom;
 use cpu::Cpu;
-use screen::*;
+use input::{Action, Bindings, Hotkey, Input};
+use audio::Audio;
+use speed::Speed;
+use rewind::Rewind;
 
 
-const M_CYCLE_LENGTH: u32 = 1_000_000_000u32 / 1_048_576;
-const FRAME_LENGTH: u32 = 17555;
+const T_CYCLES_PER_FRAME: u32 = 70224;
+const FRAME_RATE: f64 = 59.7275; //4194304 Hz / 70224 t-cycles
+const MAX_FRAME_LAG: u32 = 4; //frames behind the host clock before giving up on catching up
+const DEFAULT_REWIND_SECONDS: f64 = 10.0;
 const SCREEN_WIDTH: u32 = 160;
 const SCREEN_HEIGHT: u32 = 144;
 const RENDER_SCALE: u32 = 4;
+const SAVE_INTERVAL: u32 = 300; //frames between flushes of battery-backed ram
 
 fn main() {
 	let arguments: Vec<String> = std::env::args().collect();
-	assert!(arguments.len() == 2, "Not enough args");
+	assert!(arguments.len() >= 2, "Not enough args");
 	let rom: Rom = Rom::load_rom(arguments[1].clone()).ok()
 		.expect("Failed to load Gameboy ROM");
 	println!("File is a valid Gameboy ROM");
@@ -43,31 +64,229 @@ fn main() {
         .unwrap();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let mut cycle_count = 0;
 
-    let mut renderer: Renderer = Renderer::new(window);
-    let mut screen: Screen = Screen::new();
+    let bindings = match option_value(&arguments, "--bindings") {
+        Some(path) => Bindings::load(Path::new(path)).expect("Failed to load key bindings"),
+        None => Default::default(),
+    };
+    let mut input = Input::new(bindings, sdl_context.game_controller().unwrap());
+    let mut frame_count = 0;
+
+    let palette = match option_value(&arguments, "--palette") {
+        Some(scheme) => Palette::parse(scheme).expect("Invalid palette, use dmg, pocket, light or four RRGGBB colors"),
+        None => Palette::pocket(),
+    };
+    let canvas = window.into_canvas().build().unwrap();
+    let texture_creator = canvas.texture_creator();
+    let mut renderer: Renderer = Renderer::new(canvas, &texture_creator, palette);
 
     let mut cpu: Cpu = Cpu::new(rom); 
+    cpu.set_rtc_wall_clock(arguments[2..].iter().any(|argument| argument == "--rtc-wall-clock"));
+
+    let mut audio = match sdl_context.audio().and_then(|subsystem| Audio::new(&subsystem, apu::APU_CLOCK)) {
+        Ok(audio) => {
+            cpu.set_sample_rate(apu::APU_CLOCK); //one sample per m-cycle, resampled for the device
+            Some(audio)
+        }
+        Err(error) => {
+            println!("Failed to open audio device, continuing without sound: {}", error);
+            None
+        }
+    };
+    let audio_sync = audio.is_some() && arguments[2..].iter().any(|argument| argument == "--audio-sync");
+    let mut samples: Vec<f32> = Vec::new();
+
+    let save_path = Path::new(&arguments[1]).with_extension("sav");
+    let saving = match cpu.load_save(&save_path) {//never overwrite a save that couldn't be read with blank ram
+        Ok(()) => true,
+        Err(error) => {
+            println!("Failed to load save file {}, battery saving is disabled this session: {}", save_path.display(), error);
+            false
+        }
+    };
 
+    let mut speed = match option_value(&arguments, "--speed") {
+        Some(value) => Speed::parse(value).expect("Invalid speed, use a multiplier from 0.25 to 4, or uncapped"),
+        None => Speed::new(),
+    };
+    let frame_skip = arguments[2..].iter().any(|argument| argument == "--frame-skip");
+    let rewind_seconds = match option_value(&arguments, "--rewind-seconds") {
+        Some(value) => value.parse().expect("Invalid rewind length, use a number of seconds"),
+        None => DEFAULT_REWIND_SECONDS,
+    };
+    let rewind_interval = match option_value(&arguments, "--rewind-interval") {
+        Some(value) => value.parse().expect("Invalid rewind interval, use a number of frames"),
+        None => 1,
+    };
+    let mut rewind = Rewind::new(rewind_seconds, FRAME_RATE, rewind_interval);
+    let mut rewinding = false; //the rewind key is held
+    let mut paused = false;
+    let mut advance_frame = false; //run a single frame while paused
+    renderer.set_title(&window_title(&speed, paused));
+
+    let base_frame_duration = Duration::from_secs_f64(1.0 / FRAME_RATE);
+    let mut frame_duration = speed.frame_duration(base_frame_duration);
+    let mut next_frame = Instant::now() + frame_duration;
+    let mut last_render = Instant::now();
+    let mut leftover_cycles = 0; //t-cycles run past the end of the previous frame
     'running: loop {
-        cycle_count = cycle_count + 1;
+        let mut speed_changed = false;
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'running
                 },
-                _ => {}
+                _ => {
+                    for (action, pressed) in input.handle_event(&event) {
+                        match action {
+                            Action::Button(button) if pressed => cpu.press_button(button),
+                            Action::Button(button) => cpu.release_button(button),
+                            Action::Hotkey(Hotkey::Slower) if pressed => {
+                                speed.slower();
+                                speed_changed = true;
+                            }
+                            Action::Hotkey(Hotkey::Faster) if pressed => {
+                                speed.faster();
+                                speed_changed = true;
+                            }
+                            Action::Hotkey(Hotkey::ToggleLimiter) if pressed => {
+                                speed.toggle_limiter();
+                                speed_changed = true;
+                            }
+                            Action::Hotkey(Hotkey::Pause) if pressed => {
+                                paused = !paused;
+                                next_frame = Instant::now() + frame_duration;
+                                renderer.set_title(&window_title(&speed, paused));
+                            }
+                            Action::Hotkey(Hotkey::FrameAdvance) if pressed && paused => {//one frame per press, key repeat is ignored
+                                advance_frame = true;
+                            }
+                            Action::Hotkey(Hotkey::Rewind) => {//held, runs backwards until released
+                                rewinding = pressed;
+                            }
+                            Action::Hotkey(Hotkey::SaveState(slot)) if pressed => {
+                                save_state(&cpu, &state_path(&arguments[1], slot));
+                            }
+                            Action::Hotkey(Hotkey::LoadState(slot)) if pressed => {
+                                load_state(&mut cpu, &state_path(&arguments[1], slot));
+                            }
+                            Action::Hotkey(_) => {}
+                        }
+                    }
+                }
             }
         }
 
-        cpu.exec(&mut screen);
+        if speed_changed {
+            frame_duration = speed.frame_duration(base_frame_duration);
+            next_frame = Instant::now() + frame_duration;
+            renderer.set_title(&window_title(&speed, paused));
+        }
 
-		if cycle_count == FRAME_LENGTH {
-			cycle_count = 0;
-			renderer.render(Screen::test_screen());
+        if paused && !advance_frame {//keep servicing events so the window stays responsive
+            spin_sleep::sleep(base_frame_duration);
+            continue;
+        }
+        let stepping = advance_frame;
+        advance_frame = false;
+
+        if rewinding && !stepping {//the restored state includes its framebuffer
+            rewind.step_back(&mut cpu);
+            renderer.render(cpu.framebuffer());
+        }
+        else {
+            leftover_cycles = run_frame(&mut cpu, leftover_cycles);
+            let fast = !speed.limited() || speed.multiplier() > 1.0;
+            if cpu.poll_frame() && (stepping || !(frame_skip && fast && last_render.elapsed() < base_frame_duration)) {//skipped frames are never shown faster than real time
+                renderer.render(cpu.framebuffer());
+                last_render = Instant::now();
+            }
+            rewind.record(&cpu); //after polling, so a restored snapshot doesn't present its frame a second time
+        }
+		if let Some(rumble) = cpu.poll_rumble() {
+			input.set_rumble(rumble);
+		}
+		if let Some(audio) = audio.as_mut() {
+			cpu.drain_samples(&mut samples);
+			if speed.limited() {//pitch follows the multiplier, uncapped audio is skipped entirely
+				audio.set_speed(speed.multiplier());
+				audio.queue(&samples);
+			}
+			samples.clear();
+		}
+
+		frame_count += 1;
+		if frame_count == SAVE_INTERVAL {
+			frame_count = 0;
+			if saving && cpu.save_dirty() {
+				write_save(&mut cpu, &save_path);
+			}
 		}
-        spin_sleep::sleep(Duration::new(0, M_CYCLE_LENGTH));
+
+        if stepping || (!speed.limited() && !rewinding) {
+            continue;
+        }
+        if audio_sync && !rewinding {//the device's playback rate paces emulation instead of the host clock
+            audio.as_ref().unwrap().wait();
+            continue;
+        }
+        let duration = if rewinding { base_frame_duration } else { frame_duration }; //rewind always plays back at normal speed
+        let now = Instant::now();
+        if now < next_frame {
+            spin_sleep::sleep(next_frame - now);
+        }
+        else if now - next_frame > duration * MAX_FRAME_LAG {//too far behind, e.g. after the window was dragged
+            next_frame = now;
+        }
+        next_frame += duration;
+    }
+    if saving {
+        write_save(&mut cpu, &save_path);
+    }
+}
+
+fn window_title(speed: &Speed, paused: bool) -> String {
+    if paused {
+        return format!("JAGE ({}, paused)", speed.label());
+    }
+    format!("JAGE ({})", speed.label())
+}
+
+fn run_frame(cpu: &mut Cpu, leftover_cycles: u32) -> u32 {//runs one frame's worth of t-cycles, returns the overshoot
+    let mut cycles = leftover_cycles;
+    while cycles < T_CYCLES_PER_FRAME {
+        let m_cycles = cpu.exec() as u32;
+        cycles += if cpu.double_speed() { m_cycles * 2 } else { m_cycles * 4 };
+    }
+    cycles - T_CYCLES_PER_FRAME
+}
+
+fn state_path(rom_path: &str, slot: u8) -> PathBuf {
+    Path::new(rom_path).with_extension(format!("ss{}", slot))
+}
+
+fn save_state(cpu: &Cpu, path: &Path) {
+    match fs::write(path, cpu.save_state()) {
+        Ok(()) => println!("Saved state to {}", path.display()),
+        Err(error) => println!("Failed to save state to {}: {}", path.display(), error),
+    }
+}
+
+fn load_state(cpu: &mut Cpu, path: &Path) {
+    match fs::read(path).and_then(|data| cpu.load_state(&data)) {
+        Ok(()) => println!("Loaded state from {}", path.display()),
+        Err(error) => println!("Failed to load state from {}: {}", path.display(), error),
+    }
+}
+
+fn option_value<'a>(arguments: &'a [String], name: &str) -> Option<&'a str> {//reads --name=value style options
+    arguments[2..].iter()
+        .find_map(|argument| argument.strip_prefix(name)?.strip_prefix('='))
+}
+
+fn write_save(cpu: &mut Cpu, save_path: &Path) {
+    if let Err(error) = cpu.write_save(save_path) {
+        println!("Failed to write save file {}: {}", save_path.display(), error);
     }
 }
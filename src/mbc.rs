@@ -0,0 +1,431 @@
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+use crate::Rom;
+use crate::rtc::Rtc;
+use crate::state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const MBC2_RAM_SIZE: usize = 512;
+const RTC_TRAILER_SIZE: usize = 48;
+const OLD_RTC_TRAILER_SIZE: usize = 44;
+
+#[derive(Copy, Clone, PartialEq)]
+enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Unimplemented
+}
+
+fn match_mbc_kind(mbc_type: u8) -> MbcKind {
+    match mbc_type {
+        0x00 | 0x08 | 0x09 => MbcKind::RomOnly,
+        0x01..=0x03 => MbcKind::Mbc1,
+        0x05 | 0x06 => MbcKind::Mbc2,
+        0x0F..=0x13 => MbcKind::Mbc3,
+        0x19..=0x1E => MbcKind::Mbc5,
+        _ => MbcKind::Unimplemented
+    }
+}
+
+pub struct Mbc {
+    mbc_type: u8,
+    kind: MbcKind,
+    active_bank: u16, //lower rom bank register, 9 bits on mbc5
+    upper_bank: u8, //mbc1 2-bit register for a ram bank or the upper rom bank bits, mbc3 ram bank or rtc register, mbc5 ram bank
+    banking_mode: u8, //mbc1 mode select, 1 applies upper_bank to $0000-$3FFF and ram
+    ram_enabled: bool,
+    multicart: bool, //mbc1m wires only 4 bits of the lower rom bank register
+    rom: Rom,
+    ram: Vec<u8>,
+    ram_dirty: bool, //ram or rtc was written since the last save
+    rtc: Option<Rtc>,
+    has_rumble: bool,
+    rumble: bool, //state of the mbc5 rumble motor
+    rumble_changed: bool,
+}
+
+impl Mbc {
+    pub fn new(rom: Rom) -> Mbc {
+        let mbc_type = rom.cartridge_type;
+        let kind = match_mbc_kind(mbc_type);
+        if kind == MbcKind::Unimplemented {//warned once here rather than on every register write
+            println!("Unimplemented MBC {:#04X?}, bank switching is ignored", mbc_type);
+        }
+        let ram = match kind {
+            MbcKind::Mbc2 => vec![0; MBC2_RAM_SIZE], //built into the mbc, the header reports none
+            _ => vec![0; rom.ram_size_bytes()],
+        };
+        let multicart = kind == MbcKind::Mbc1 && is_mbc1_multicart(&rom);
+        let rtc = match mbc_type {
+            0x0F | 0x10 => Some(Rtc::new()),
+            _ => None,
+        };
+        Mbc {
+            mbc_type,
+            kind,
+            active_bank: 1,
+            upper_bank: 0,
+            banking_mode: 0,
+            ram_enabled: false,
+            multicart,
+            rom,
+            ram,
+            ram_dirty: false,
+            rtc,
+            has_rumble: (0x1C..=0x1E).contains(&mbc_type),
+            rumble: false,
+            rumble_changed: false,
+        }
+    }
+    pub fn rom(&self) -> &Rom {
+        &self.rom
+    }
+    pub fn set_rtc_wall_clock(&mut self, enabled: bool) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_wall_clock(enabled);
+        }
+    }
+    pub fn load_save(&mut self, path: &Path) -> Result<(), Error> {//restores battery-backed ram and rtc from a .sav file
+        if !self.rom.has_battery() || !path.exists() {
+            return Ok(());
+        }
+        let data = fs::read(path)?;
+        if data.len() < self.ram.len() {
+            return Err(Error::new(std::io::ErrorKind::InvalidData, "Save file is shorter than the cartridge ram"));
+        }
+        let ram_length = self.ram.len();
+        self.ram.copy_from_slice(&data[..ram_length]);
+
+        if let Some(rtc) = &mut self.rtc {
+            let trailer = &data[ram_length..];
+            if trailer.len() == RTC_TRAILER_SIZE || trailer.len() == OLD_RTC_TRAILER_SIZE {
+                rtc.load_save_trailer(trailer);
+            }
+        }
+        Ok(())
+    }
+    pub fn write_save(&mut self, path: &Path) -> Result<(), Error> {
+        if !self.rom.has_battery() || (self.ram.is_empty() && self.rtc.is_none()) {
+            return Ok(());
+        }
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.save_trailer());
+        }
+        fs::write(path, data)?;
+        self.ram_dirty = false;
+        Ok(())
+    }
+    pub fn save_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.active_bank);
+        writer.u8(self.upper_bank);
+        writer.u8(self.banking_mode);
+        writer.bool(self.ram_enabled);
+        writer.bytes(&self.ram);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+        writer.bool(self.rumble);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.active_bank = reader.u16()?;
+        self.upper_bank = reader.u8()?;
+        self.banking_mode = reader.u8()?;
+        self.ram_enabled = reader.bool()?;
+        reader.bytes(&mut self.ram)?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        self.rumble = reader.bool()?;
+        self.rumble_changed = true;
+        self.ram_dirty = true; //the loaded ram should reach the .sav file too
+        Ok(())
+    }
+    pub fn poll_rumble(&mut self) -> Option<bool> {//returns the new motor state if it changed since the last poll
+        if !self.rumble_changed {
+            return None;
+        }
+        self.rumble_changed = false;
+        Some(self.rumble)
+    }
+    pub fn tick(&mut self, dots: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(dots);
+        }
+    }
+    pub fn read_rom(&self, address: u16) -> u8 {//$0000-$7FFF
+        let bank = match address {
+            0x0000..=0x3FFF => self.low_rom_bank(),
+            _ => self.high_rom_bank(),
+        };
+        let bank_count = self.rom.data.len() / ROM_BANK_SIZE;
+        let offset = (bank % bank_count) * ROM_BANK_SIZE;
+        self.rom.data[offset + (address as usize % ROM_BANK_SIZE)]
+    }
+    pub fn write_rom(&mut self, address: u16, data: u8) {//writes to rom address the mbc's registers
+        match self.kind {
+            MbcKind::RomOnly => {}
+            MbcKind::Mbc1 => {
+                match address {
+                    0x0000..=0x1FFF => {
+                        self.ram_enabled = data & 0x0F == 0x0A;
+                    }
+                    0x2000..=0x3FFF => {
+                        let mut bank = data & 0b00011111;
+                        if bank == 0 {//the 0->1 quirk looks at all 5 bits, even on multicarts
+                            bank = 1;
+                        }
+                        self.active_bank = bank as u16;
+                    }
+                    0x4000..=0x5FFF => {
+                        self.upper_bank = data & 0b00000011;
+                    }
+                    _ => {
+                        self.banking_mode = data & 1;
+                    }
+                }
+            }
+            MbcKind::Mbc2 => {
+                if address > 0x3FFF {
+                    return;
+                }
+                if address & 0x0100 == 0 {//address bit 8 selects between the two registers
+                    self.ram_enabled = data & 0x0F == 0x0A;
+                }
+                else {
+                    let mut bank = data & 0b00001111;
+                    if bank == 0 {
+                        bank = 1;
+                    }
+                    self.active_bank = bank as u16;
+                }
+            }
+            MbcKind::Mbc3 => {
+                match address {
+                    0x0000..=0x1FFF => {
+                        self.ram_enabled = data & 0x0F == 0x0A;
+                    }
+                    0x2000..=0x3FFF => {
+                        let mut bank = data & 0b01111111;
+                        if bank == 0 {
+                            bank = 1;
+                        }
+                        self.active_bank = bank as u16;
+                    }
+                    0x4000..=0x5FFF => {
+                        self.upper_bank = data & 0b00001111;
+                    }
+                    _ => {
+                        if let Some(rtc) = &mut self.rtc {
+                            rtc.write_latch(data);
+                        }
+                    }
+                }
+            }
+            MbcKind::Mbc5 => {
+                match address {
+                    0x0000..=0x1FFF => {
+                        self.ram_enabled = data & 0x0F == 0x0A;
+                    }
+                    0x2000..=0x2FFF => {
+                        self.active_bank = (self.active_bank & 0x100) | data as u16;
+                    }
+                    0x3000..=0x3FFF => {
+                        self.active_bank = (self.active_bank & 0xFF) | ((data as u16 & 1) << 8);
+                    }
+                    0x4000..=0x5FFF => {
+                        if self.has_rumble {//bit 3 drives the motor instead of selecting ram
+                            let rumble = data & 0b00001000 != 0;
+                            if rumble != self.rumble {
+                                self.rumble = rumble;
+                                self.rumble_changed = true;
+                            }
+                            self.upper_bank = data & 0b00000111;
+                        }
+                        else {
+                            self.upper_bank = data & 0b00001111;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            MbcKind::Unimplemented => {}
+        }
+    }
+    pub fn read_ram(&self, address: u16) -> u8 {//$A000-$BFFF
+        if let Some(register) = self.rtc_register() {
+            return self.rtc.as_ref().map_or(0xFF, |rtc| rtc.read(register));
+        }
+        match self.ram_index(address) {
+            Some(index) if self.kind == MbcKind::Mbc2 => self.ram[index] | 0xF0, //only the low nibble exists
+            Some(index) => self.ram[index],
+            None => 0xFF,
+        }
+    }
+    pub fn write_ram(&mut self, address: u16, data: u8) {
+        if let Some(register) = self.rtc_register() {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.write(register, data);
+                self.ram_dirty = true;
+            }
+            return;
+        }
+        if let Some(index) = self.ram_index(address) {
+            self.ram[index] = data;
+            self.ram_dirty = true;
+        }
+    }
+
+    fn low_rom_bank(&self) -> usize {//bank mapped to $0000-$3FFF
+        match self.kind {
+            MbcKind::Mbc1 if self.banking_mode == 1 => (self.upper_bank as usize) << self.upper_bank_shift(),
+            _ => 0,
+        }
+    }
+    fn high_rom_bank(&self) -> usize {//bank mapped to $4000-$7FFF
+        match self.kind {
+            MbcKind::RomOnly => 1,
+            MbcKind::Mbc1 => {
+                let shift = self.upper_bank_shift();
+                let lower = self.active_bank as usize & ((1 << shift) - 1);
+                ((self.upper_bank as usize) << shift) | lower
+            }
+            MbcKind::Mbc2 | MbcKind::Mbc3 | MbcKind::Mbc5 | MbcKind::Unimplemented => self.active_bank as usize,
+        }
+    }
+    fn rtc_register(&self) -> Option<u8> {//mbc3 maps an rtc register to $A000-$BFFF instead of ram
+        if self.kind == MbcKind::Mbc3 && self.ram_enabled && (0x08..=0x0C).contains(&self.upper_bank) {
+            return Some(self.upper_bank);
+        }
+        None
+    }
+    fn upper_bank_shift(&self) -> usize {
+        if self.multicart {
+            return 4;
+        }
+        5
+    }
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let bank = match self.kind {
+            MbcKind::RomOnly => 0,
+            MbcKind::Mbc1 => {
+                if !self.ram_enabled {
+                    return None;
+                }
+                if self.banking_mode == 1 {
+                    self.upper_bank as usize
+                }
+                else {
+                    0
+                }
+            }
+            MbcKind::Mbc2 => {
+                if !self.ram_enabled {
+                    return None;
+                }
+                return Some((address & 0x01FF) as usize); //512 half-bytes, echoed through the whole area
+            }
+            MbcKind::Mbc3 => {
+                if !self.ram_enabled {
+                    return None;
+                }
+                (self.upper_bank & 0b00000011) as usize
+            }
+            MbcKind::Mbc5 => {
+                if !self.ram_enabled {
+                    return None;
+                }
+                self.upper_bank as usize
+            }
+            MbcKind::Unimplemented => 0,
+        };
+        let index = bank * RAM_BANK_SIZE + (address - 0xA000) as usize;
+        Some(index % self.ram.len())
+    }
+}
+
+fn is_mbc1_multicart(rom: &Rom) -> bool {//mbc1m carts are 1 MiB and repeat the boot logo in the game at bank $10
+    rom.data.len() == 64 * ROM_BANK_SIZE && rom.logo_at(0x10 * ROM_BANK_SIZE + 0x0104)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banked_mbc(cartridge_type: u8, banks: usize) -> Mbc {//each bank starts with its own 16-bit bank number
+        let mut rom = Rom::default();
+        rom.data = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom.data[bank * ROM_BANK_SIZE] = bank as u8;
+            rom.data[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom.cartridge_type = cartridge_type;
+        Mbc::new(rom)
+    }
+
+    fn mapped_bank(mbc: &Mbc, address: u16) -> usize {
+        mbc.read_rom(address) as usize | (mbc.read_rom(address + 1) as usize) << 8
+    }
+
+    #[test]
+    fn mbc1_bank_zero_selects_bank_one() {
+        let mut mbc = banked_mbc(0x01, 32);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 1);
+        mbc.write_rom(0x2000, 0x1F);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0x1F);
+    }
+
+    #[test]
+    fn mbc1_upper_bits_extend_the_rom_bank() {
+        let mut mbc = banked_mbc(0x01, 128);
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0x45);
+        assert_eq!(mapped_bank(&mbc, 0x0000), 0); //mode 0 keeps bank 0 at $0000
+
+        mbc.write_rom(0x2000, 0x00); //the 0->1 quirk only sees the lower 5 bits, so $40 reads as $41
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0x41);
+
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mapped_bank(&mbc, 0x0000), 0x40);
+    }
+
+    #[test]
+    fn mbc1_bank_wraps_to_rom_size() {
+        let mut mbc = banked_mbc(0x01, 4);
+        mbc.write_rom(0x2000, 0x06);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 2);
+    }
+
+    #[test]
+    fn mbc5_allows_bank_zero() {
+        let mut mbc = banked_mbc(0x19, 64);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0);
+    }
+
+    #[test]
+    fn mbc5_ninth_bank_bit() {
+        let mut mbc = banked_mbc(0x19, 512);
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0x105);
+        mbc.write_rom(0x2000, 0xFF);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0x1FF);
+        mbc.write_rom(0x3000, 0x00);
+        assert_eq!(mapped_bank(&mbc, 0x4000), 0xFF);
+    }
+}
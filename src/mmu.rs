@@ -0,0 +1,201 @@
+use std::io::Error;
+use std::path::Path;
+
+use crate::Rom;
+use crate::mbc::Mbc;
+use crate::ppu::Ppu;
+use crate::timer::Timer;
+use crate::apu::{self, Apu};
+use crate::joypad::{Button, Joypad};
+use crate::interrupts::Interrupts;
+use crate::state::{self, StateReader, StateWriter};
+
+pub struct Mmu {
+    mbc: Mbc, //$0000-$7FFF, $A000-$BFFF
+    pub ppu: Ppu, //$8000-$9FFF, $FE00-$FE9F, lcd registers
+    wram: [u8; 0x2000], //$C000-$DFFF, echoed at $E000-$FDFF
+    joypad: Joypad, //$FF00
+    pub timer: Timer, //$FF04-$FF07
+    apu: Apu, //$FF10-$FF26, $FF30-$FF3F
+    div_apu_bit: u16, //counter bit whose falling edge clocks the frame sequencer, bit 12 or bit 13 in double speed
+    io: [u8; 0x80], //$FF00-$FF7F, for registers without their own subsystem
+    hram: [u8; 0x7F], //$FF80-$FFFE
+    pub interrupts: Interrupts, //$FF0F, $FFFF
+}
+
+impl Mmu {
+    pub fn new(rom: Rom) -> Mmu {
+        Mmu {
+            mbc: Mbc::new(rom),
+            ppu: Ppu::new(),
+            wram: [0; 0x2000],
+            joypad: Joypad::new(),
+            timer: Timer::new(),
+            apu: Apu::new(apu::DEFAULT_SAMPLE_RATE),
+            div_apu_bit: 12,
+            io: [0xFF; 0x80],
+            hram: [0; 0x7F],
+            interrupts: Interrupts::new(),
+        }
+    }
+    pub fn rom(&self) -> &Rom {
+        self.mbc.rom()
+    }
+    pub fn set_rtc_wall_clock(&mut self, enabled: bool) {
+        self.mbc.set_rtc_wall_clock(enabled);
+    }
+    pub fn load_save(&mut self, path: &Path) -> Result<(), Error> {
+        self.mbc.load_save(path)
+    }
+    pub fn write_save(&mut self, path: &Path) -> Result<(), Error> {
+        self.mbc.write_save(path)
+    }
+    pub fn save_dirty(&self) -> bool {
+        self.mbc.save_dirty()
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.mbc.save_state(writer);
+        self.ppu.save_state(writer);
+        writer.bytes(&self.wram);
+        self.joypad.save_state(writer);
+        self.timer.save_state(writer);
+        self.apu.save_state(writer);
+        writer.u8(self.div_apu_bit as u8);
+        writer.bytes(&self.io);
+        writer.bytes(&self.hram);
+        self.interrupts.save_state(writer);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.mbc.load_state(reader)?;
+        self.ppu.load_state(reader)?;
+        reader.bytes(&mut self.wram)?;
+        self.joypad.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.div_apu_bit = reader.u8()? as u16;
+        state::check(self.div_apu_bit == 12 || self.div_apu_bit == 13, "div-apu bit")?;
+        reader.bytes(&mut self.io)?;
+        reader.bytes(&mut self.hram)?;
+        self.interrupts.load_state(reader)?;
+        Ok(())
+    }
+    pub fn poll_rumble(&mut self) -> Option<bool> {
+        self.mbc.poll_rumble()
+    }
+    pub fn press_button(&mut self, button: Button) {
+        self.joypad.press(button, &mut self.interrupts);
+    }
+    pub fn release_button(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+    pub fn button_pressed_since_stop(&self) -> bool {
+        self.joypad.press_latched()
+    }
+    pub fn enter_stop(&mut self) {
+        self.joypad.clear_press_latch();
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+    pub fn drain_samples(&mut self, output: &mut Vec<f32>) {
+        self.apu.drain_samples(output);
+    }
+    pub fn tick(&mut self, m_cycles: u32, double_speed: bool) {//advances everything that runs alongside the cpu
+        let dots = if double_speed { m_cycles * 2 } else { m_cycles * 4 };
+        self.div_apu_bit = if double_speed { 13 } else { 12 };
+        let old_counter = self.timer.counter();
+        self.timer.tick(m_cycles, &mut self.interrupts); //the timer follows the cpu clock in double speed
+        if self.div_apu_falling_edge(old_counter) {
+            self.apu.clock_frame_sequencer();
+        }
+        self.apu.tick(dots); //the apu does not speed up in double speed
+        self.ppu.tick(dots, &mut self.interrupts);
+        self.mbc.tick(dots);
+    }
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => self.mbc.read_rom(address),
+            0x8000..=0x9FFF => self.ppu.screen.vram[(address - 0x8000) as usize],
+            0xA000..=0xBFFF => self.mbc.read_ram(address),
+            0xC000..=0xDFFF => self.wram[(address - 0xC000) as usize],
+            0xE000..=0xFDFF => self.wram[(address - 0xE000) as usize],
+            0xFE00..=0xFE9F => self.ppu.screen.oam[(address - 0xFE00) as usize],
+            0xFEA0..=0xFEFF => if self.ppu.oam_locked() { 0xFF } else { 0x00 }, //unusable, open bus on DMG
+            0xFF00 => self.joypad.read(),
+            0xFF04 => self.timer.read_div(),
+            0xFF05 => self.timer.tima,
+            0xFF06 => self.timer.tma,
+            0xFF07 => self.timer.read_tac(),
+            0xFF0F => self.interrupts.read_flags(),
+            0xFF10..=0xFF3F => self.apu.read(address),
+            0xFF40 => self.ppu.screen.lcdc,
+            0xFF41 => self.ppu.read_stat(),
+            0xFF42 => self.ppu.screen.scy,
+            0xFF43 => self.ppu.screen.scx,
+            0xFF44 => self.ppu.screen.ly,
+            0xFF45 => self.ppu.screen.lyc,
+            0xFF46 => self.ppu.screen.dma,
+            0xFF47 => self.ppu.screen.bgp,
+            0xFF48 => self.ppu.screen.obp0,
+            0xFF49 => self.ppu.screen.obp1,
+            0xFF4A => self.ppu.screen.wy,
+            0xFF4B => self.ppu.screen.wx,
+            0xFF01..=0xFF7F => self.io[(address - 0xFF00) as usize],
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize],
+            0xFFFF => self.interrupts.enable,
+        }
+    }
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x7FFF => self.mbc.write_rom(address, data),
+            0x8000..=0x9FFF => self.ppu.screen.vram[(address - 0x8000) as usize] = data,
+            0xA000..=0xBFFF => self.mbc.write_ram(address, data),
+            0xC000..=0xDFFF => self.wram[(address - 0xC000) as usize] = data,
+            0xE000..=0xFDFF => self.wram[(address - 0xE000) as usize] = data,
+            0xFE00..=0xFE9F => self.ppu.screen.oam[(address - 0xFE00) as usize] = data,
+            0xFEA0..=0xFEFF => {}, //unusable, writes are ignored
+            0xFF00 => self.joypad.write(data, &mut self.interrupts),
+            0xFF04 => {
+                let old_counter = self.timer.counter();
+                self.timer.write_div();
+                if self.div_apu_falling_edge(old_counter) {
+                    self.apu.clock_frame_sequencer();
+                }
+            }
+            0xFF05 => self.timer.write_tima(data),
+            0xFF06 => self.timer.write_tma(data),
+            0xFF07 => self.timer.write_tac(data),
+            0xFF0F => self.interrupts.write_flags(data),
+            0xFF10..=0xFF3F => self.apu.write(address, data),
+            0xFF40 => self.ppu.write_lcdc(data),
+            0xFF41 => self.ppu.write_stat(data, &mut self.interrupts),
+            0xFF42 => self.ppu.screen.scy = data,
+            0xFF43 => self.ppu.screen.scx = data,
+            0xFF44 => {}, //ly is read-only
+            0xFF45 => self.ppu.write_lyc(data, &mut self.interrupts),
+            0xFF46 => self.oam_dma(data),
+            0xFF47 => self.ppu.screen.bgp = data,
+            0xFF48 => self.ppu.screen.obp0 = data,
+            0xFF49 => self.ppu.screen.obp1 = data,
+            0xFF4A => self.ppu.screen.wy = data,
+            0xFF4B => self.ppu.screen.wx = data,
+            0xFF01..=0xFF7F => self.io[(address - 0xFF00) as usize] = data,
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize] = data,
+            0xFFFF => self.interrupts.enable = data,
+        }
+    }
+    fn div_apu_falling_edge(&self, old_counter: u16) -> bool {
+        let bit = 1 << self.div_apu_bit;
+        old_counter & bit != 0 && self.timer.counter() & bit == 0
+    }
+    fn oam_dma(&mut self, data: u8) {//copies $XX00-$XX9F into oam, done at once rather than over 160 m-cycles
+        self.ppu.screen.dma = data;
+        let source = (data as u16) << 8;
+        for i in 0..0xA0 {
+            self.ppu.screen.oam[i as usize] = self.read(source + i);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::io::Error;
+
+use crate::state::{self, StateReader, StateWriter};
+use crate::screen::{Screen, Sprite};
+use crate::interrupts::{Interrupt, Interrupts};
+
+pub const LCD_WIDTH: usize = 160;
+pub const LCD_HEIGHT: usize = 144;
+const DOTS_PER_LINE: u32 = 456;
+const DOTS_PER_FRAME: u32 = DOTS_PER_LINE * LINES_PER_FRAME as u32;
+const OAM_SCAN_END: u32 = 80;
+const PIXEL_TRANSFER_END: u32 = OAM_SCAN_END + 172;
+const LINES_PER_FRAME: u8 = 154;
+const SPRITE_COUNT: usize = 40;
+const SPRITES_PER_LINE: usize = 10;
+const WINDOW_X_OFFSET: i32 = 7;
+const WINDOW_MAX_X: u8 = 166;
+
+const MODE_HBLANK: u8 = 0;
+const MODE_VBLANK: u8 = 1;
+const MODE_OAM_SCAN: u8 = 2;
+const MODE_PIXEL_TRANSFER: u8 = 3;
+
+const STAT_LYC_INTERRUPT: u8 = 0b01000000;
+const STAT_OAM_INTERRUPT: u8 = 0b00100000;
+const STAT_VBLANK_INTERRUPT: u8 = 0b00010000;
+const STAT_HBLANK_INTERRUPT: u8 = 0b00001000;
+const STAT_LYC_EQUAL: u8 = 0b00000100;
+
+const SPRITE_BG_PRIORITY: u8 = 0b10000000;
+const SPRITE_Y_FLIP: u8 = 0b01000000;
+const SPRITE_X_FLIP: u8 = 0b00100000;
+const SPRITE_PALETTE: u8 = 0b00010000;
+
+pub struct Ppu {
+    pub screen: Screen,
+    pub framebuffer: [u8; LCD_WIDTH * LCD_HEIGHT], //shades 0-3 after the game's palettes, one byte per pixel
+    dots: u32, //dots into the current line, or into the frame while the lcd is off
+    stat_line: bool, //the stat interrupt fires on a rising edge of this
+    frame_ready: bool,
+    window_line: usize, //internal window line counter, only advances on lines where the window was drawn
+    window_triggered: bool, //ly matched wy at some point this frame
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            screen: Screen::new(),
+            framebuffer: [0; LCD_WIDTH * LCD_HEIGHT],
+            dots: 0,
+            stat_line: false,
+            frame_ready: false,
+            window_line: 0,
+            window_triggered: false,
+        }
+    }
+    pub fn poll_frame(&mut self) -> bool {//true once per completed frame
+        let frame_ready = self.frame_ready;
+        self.frame_ready = false;
+        frame_ready
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.screen.save_state(writer);
+        writer.bytes(&self.framebuffer);
+        writer.u32(self.dots);
+        writer.bool(self.stat_line);
+        writer.bool(self.frame_ready);
+        writer.u32(self.window_line as u32);
+        writer.bool(self.window_triggered);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.screen.load_state(reader)?;
+        reader.bytes(&mut self.framebuffer)?;
+        self.dots = reader.u32()?;
+        self.stat_line = reader.bool()?;
+        self.frame_ready = reader.bool()?;
+        self.window_line = reader.u32()? as usize;
+        self.window_triggered = reader.bool()?;
+
+        let ly = self.screen.ly;
+        state::check(ly < LINES_PER_FRAME && (self.mode() == MODE_VBLANK || (ly as usize) < LCD_HEIGHT), "ly")?;
+        let dots_valid = if self.lcd_enabled() { self.dots < self.mode_end() } else { self.dots < DOTS_PER_FRAME };
+        state::check(dots_valid, "ppu dot counter")?;
+        state::check(self.window_line <= LCD_HEIGHT, "window line")
+    }
+    pub fn mode(&self) -> u8 {
+        self.screen.stat & 0b00000011
+    }
+    pub fn oam_locked(&self) -> bool {//the ppu owns oam during oam scan and pixel transfer
+        self.mode() == MODE_OAM_SCAN || self.mode() == MODE_PIXEL_TRANSFER
+    }
+    pub fn read_stat(&self) -> u8 {
+        self.screen.stat | 0b10000000
+    }
+    pub fn write_stat(&mut self, data: u8, interrupts: &mut Interrupts) {
+        self.screen.stat = (self.screen.stat & 0b00000111) | (data & 0b01111000);
+        self.update_stat(interrupts);
+    }
+    pub fn write_lyc(&mut self, data: u8, interrupts: &mut Interrupts) {
+        self.screen.lyc = data;
+        self.update_stat(interrupts);
+    }
+    pub fn write_lcdc(&mut self, data: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.screen.lcdc = data;
+        if was_enabled && !self.lcd_enabled() {//turning the lcd off resets it to the top of the screen
+            self.screen.ly = 0;
+            self.dots = 0;
+            self.window_line = 0;
+            self.window_triggered = false;
+            self.set_mode(MODE_HBLANK);
+            self.framebuffer = [0; LCD_WIDTH * LCD_HEIGHT];
+        }
+        else if !was_enabled && self.lcd_enabled() {
+            self.dots = 0;
+            self.set_mode(MODE_OAM_SCAN);
+        }
+    }
+    pub fn tick(&mut self, dots: u32, interrupts: &mut Interrupts) {
+        if !self.lcd_enabled() {//keep producing blank frames so the frontend stays in step
+            self.dots += dots;
+            if self.dots >= DOTS_PER_FRAME {
+                self.dots -= DOTS_PER_FRAME;
+                self.frame_ready = true;
+            }
+            return;
+        }
+
+        let mut remaining = dots;
+        while remaining > 0 {
+            let mode_end = self.mode_end();
+            let step = remaining.min(mode_end - self.dots);
+            self.dots += step;
+            remaining -= step;
+            if self.dots == mode_end {
+                self.next_mode(interrupts);
+            }
+        }
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.screen.lcdc & 0b10000000 != 0
+    }
+    fn mode_end(&self) -> u32 {//dot within the line where the current mode ends
+        match self.mode() {
+            MODE_OAM_SCAN => OAM_SCAN_END,
+            MODE_PIXEL_TRANSFER => PIXEL_TRANSFER_END,
+            _ => DOTS_PER_LINE,
+        }
+    }
+    fn set_mode(&mut self, mode: u8) {
+        self.screen.stat = (self.screen.stat & 0b11111100) | mode;
+    }
+    fn next_mode(&mut self, interrupts: &mut Interrupts) {
+        match self.mode() {
+            MODE_OAM_SCAN => {
+                self.set_mode(MODE_PIXEL_TRANSFER);
+            }
+            MODE_PIXEL_TRANSFER => {
+                self.render_line();
+                self.set_mode(MODE_HBLANK);
+            }
+            MODE_HBLANK => {
+                self.dots = 0;
+                self.screen.ly += 1;
+                if self.screen.ly as usize == LCD_HEIGHT {
+                    self.set_mode(MODE_VBLANK);
+                    interrupts.request(Interrupt::VBlank);
+                    self.frame_ready = true;
+                }
+                else {
+                    self.set_mode(MODE_OAM_SCAN);
+                }
+            }
+            _ => {
+                self.dots = 0;
+                self.screen.ly += 1;
+                if self.screen.ly == LINES_PER_FRAME {
+                    self.screen.ly = 0;
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.set_mode(MODE_OAM_SCAN);
+                }
+            }
+        }
+        self.update_stat(interrupts);
+    }
+    fn update_stat(&mut self, interrupts: &mut Interrupts) {//refreshes the ly=lyc flag and checks the stat interrupt sources
+        let stat = self.screen.stat;
+        let lyc_equal = self.screen.ly == self.screen.lyc;
+        if lyc_equal {
+            self.screen.stat |= STAT_LYC_EQUAL;
+        }
+        else {
+            self.screen.stat &= !STAT_LYC_EQUAL;
+        }
+
+        let stat_line = self.lcd_enabled() && (
+            (lyc_equal && stat & STAT_LYC_INTERRUPT != 0) ||
+            (self.mode() == MODE_OAM_SCAN && stat & STAT_OAM_INTERRUPT != 0) ||
+            (self.mode() == MODE_VBLANK && stat & STAT_VBLANK_INTERRUPT != 0) ||
+            (self.mode() == MODE_HBLANK && stat & STAT_HBLANK_INTERRUPT != 0)
+        );
+        if stat_line && !self.stat_line {
+            interrupts.request(Interrupt::Stat);
+        }
+        self.stat_line = stat_line;
+    }
+    fn render_line(&mut self) {
+        let ly = self.screen.ly as usize;
+        let lcdc = self.screen.lcdc;
+        let mut bg_line = [0; LCD_WIDTH]; //color indices before any palette, needed for sprite priority
+        let mut line = [0; LCD_WIDTH];
+
+        //bg rendering! the 32x32 tile map wraps around at 256 pixels in both directions
+        if lcdc & 0b00000001 != 0 {
+            let bg_tilemap = if lcdc & 0b00001000 == 0 { 0 } else { 1 };
+            let y = (ly + self.screen.scy as usize) % 256;
+            for (x, pixel) in bg_line.iter_mut().enumerate() {
+                let x = (x + self.screen.scx as usize) % 256;
+                let tile_id = self.screen.tilemap(bg_tilemap, y / 8, x / 8);
+                *pixel = self.screen.tile_pixel(self.screen.bg_tile_index(tile_id), x % 8, y % 8);
+            }
+        }
+
+        //window rendering, drawn over the bg and treated as bg for sprite priority
+        if self.screen.ly == self.screen.wy {
+            self.window_triggered = true;
+        }
+        if lcdc & 0b00100001 == 0b00100001 && self.window_triggered && self.screen.wx <= WINDOW_MAX_X {
+            let wd_tilemap = if lcdc & 0b01000000 == 0 { 0 } else { 1 };
+            let y = self.window_line;
+            let window_start = self.screen.wx as i32 - WINDOW_X_OFFSET; //negative when wx < 7, cutting off the window's left edge
+            for (x, pixel) in bg_line.iter_mut().enumerate().skip(window_start.max(0) as usize) {
+                let x = (x as i32 - window_start) as usize;
+                let tile_id = self.screen.tilemap(wd_tilemap, y / 8, x / 8);
+                *pixel = self.screen.tile_pixel(self.screen.bg_tile_index(tile_id), x % 8, y % 8);
+            }
+            self.window_line += 1;
+        }
+        if lcdc & 0b00000001 != 0 {
+            for (pixel, color) in line.iter_mut().zip(bg_line.iter()) {
+                *pixel = apply_palette(self.screen.bgp, *color);
+            }
+        }
+
+        if lcdc & 0b00000010 != 0 {
+            let sprites = self.line_sprites();
+            for (x, pixel) in line.iter_mut().enumerate() {
+                if let Some((color, sprite)) = self.sprite_pixel(&sprites, x) {
+                    if sprite.attributes & SPRITE_BG_PRIORITY != 0 && bg_line[x] != 0 {
+                        continue;
+                    }
+                    let palette = if sprite.attributes & SPRITE_PALETTE == 0 { self.screen.obp0 } else { self.screen.obp1 };
+                    *pixel = apply_palette(palette, color);
+                }
+            }
+        }
+
+        self.framebuffer[ly * LCD_WIDTH..(ly + 1) * LCD_WIDTH].copy_from_slice(&line);
+    }
+    fn sprite_height(&self) -> usize {
+        if self.screen.lcdc & 0b00000100 != 0 {
+            return 16;
+        }
+        8
+    }
+    fn line_sprites(&self) -> Vec<Sprite> {//the first 10 sprites in oam overlapping ly, in dmg drawing priority order
+        let ly = self.screen.ly as usize;
+        let height = self.sprite_height();
+        let mut sprites: Vec<Sprite> = (0..SPRITE_COUNT)
+            .map(|index| self.screen.sprite(index))
+            .filter(|sprite| ly + 16 >= sprite.y as usize && ly + 16 < sprite.y as usize + height)
+            .take(SPRITES_PER_LINE)
+            .collect();
+        sprites.sort_by_key(|sprite| sprite.x); //stable, so equal x keeps oam order
+        sprites
+    }
+    fn sprite_pixel(&self, sprites: &[Sprite], x: usize) -> Option<(u8, Sprite)> {//color index of the highest priority opaque sprite pixel
+        let ly = self.screen.ly as usize;
+        let height = self.sprite_height();
+        for sprite in sprites {
+            if x + 8 < sprite.x as usize || x >= sprite.x as usize {
+                continue;
+            }
+            let mut sprite_x = x + 8 - sprite.x as usize;
+            let mut sprite_y = ly + 16 - sprite.y as usize;
+            if sprite.attributes & SPRITE_X_FLIP != 0 {
+                sprite_x = 7 - sprite_x;
+            }
+            if sprite.attributes & SPRITE_Y_FLIP != 0 {
+                sprite_y = height - 1 - sprite_y;
+            }
+            let mut tile = sprite.tile as usize;
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let color = self.screen.tile_pixel(tile + sprite_y / 8, sprite_x, sprite_y % 8);
+            if color != 0 {
+                return Some((color, *sprite));
+            }
+        }
+        None
+    }
+}
+
+fn apply_palette(palette: u8, color: u8) -> u8 {//maps a 2-bit color index to a shade through bgp, obp0 or obp1
+    (palette >> (color * 2)) & 0b11
+}
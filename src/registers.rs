@@ -1,4 +1,7 @@
 use std::fmt;
+use std::io::Error;
+
+use crate::state::{StateReader, StateWriter};
 
 union RegisterUnion {
 	double: u16,
@@ -22,6 +25,8 @@ pub enum RegisterName {
 	PC
 }
 
+const SAVED_REGISTERS: [RegisterName; 6] = [RegisterName::AF, RegisterName::BC, RegisterName::DE, RegisterName::HL, RegisterName::SP, RegisterName::PC];
+
 pub struct Registers {
 	af: RegisterUnion,
 	bc: RegisterUnion,
@@ -91,6 +96,21 @@ impl Registers {
 			}
 		}
 	}
+	pub fn save_state(&self, writer: &mut StateWriter) {
+		for register in SAVED_REGISTERS {
+			writer.u16(self.read(register));
+		}
+	}
+	pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+		for register in SAVED_REGISTERS {
+			let mut data = reader.u16()?;
+			if let RegisterName::AF = register {
+				data &= 0xFFF0; //the low nibble of f always reads 0
+			}
+			self.write(register, data);
+		}
+		Ok(())
+	}
 }
 
 impl fmt::Display for Registers {
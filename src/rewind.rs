@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+
+use crate::cpu::Cpu;
+
+const MAX_MEMORY: usize = 256 * 1024 * 1024; //bytes of snapshots kept, however many seconds were asked for
+
+pub struct Rewind {//ring buffer of recent machine states, each stored as a compressed delta to the next newer one
+    latest: Option<Vec<u8>>, //newest snapshot, kept whole
+    deltas: VecDeque<Vec<u8>>, //xor of each older snapshot with its newer neighbour, run-length encoded, oldest first
+    delta_bytes: usize,
+    capacity: usize, //snapshots covering the configured number of seconds
+    interval: u32, //frames between snapshots
+    frames: u32, //frames since the last snapshot, or since the last step back while rewinding
+}
+
+impl Rewind {
+    pub fn new(seconds: f64, frame_rate: f64, interval: u32) -> Rewind {
+        let interval = interval.max(1);
+        Rewind {
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            capacity: (seconds * frame_rate / interval as f64).ceil() as usize,
+            interval,
+            frames: 0,
+        }
+    }
+    pub fn record(&mut self, cpu: &Cpu) {//call once per emulated frame
+        self.frames += 1;
+        if self.frames < self.interval || self.capacity == 0 {
+            return;
+        }
+        self.frames = 0;
+
+        let state = cpu.save_state();
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                let delta = compress_delta(&latest, &state);
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            }
+            else {//a different layout can't be diffed, start over
+                self.clear();
+            }
+        }
+        self.latest = Some(state);
+
+        while self.deltas.len() + 1 > self.capacity || self.delta_bytes + self.latest_bytes() > MAX_MEMORY {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.delta_bytes -= oldest.len(),
+                None => break,
+            }
+        }
+    }
+    pub fn step_back(&mut self, cpu: &mut Cpu) {//call once per host frame while rewinding, stops at the oldest snapshot
+        self.frames += 1;
+        if self.frames < self.interval {//snapshots are spaced out, so hold each one to rewind in real time
+            return;
+        }
+        self.frames = 0;
+
+        let Some(mut state) = self.latest.take() else {
+            return;
+        };
+        if let Some(delta) = self.deltas.pop_back() {//latest is where the machine already is, so start one snapshot older
+            self.delta_bytes -= delta.len();
+            apply_delta(&mut state, &delta);
+        }
+        //with no deltas left this is the oldest state, so holding the key stays on it
+        if let Err(error) = cpu.load_snapshot(&state) {
+            println!("Failed to rewind: {}", error);
+            self.clear();
+            return;
+        }
+        self.latest = Some(state);
+    }
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    fn latest_bytes(&self) -> usize {
+        self.latest.as_ref().map_or(0, |latest| latest.len())
+    }
+}
+
+fn compress_delta(old: &[u8], new: &[u8]) -> Vec<u8> {//xors the states and run-length encodes the zero runs, as a 0 followed by the run length
+    let mut output = Vec::new();
+    let mut zeros: u8 = 0;
+    for (a, b) in old.iter().zip(new.iter()) {
+        let byte = a ^ b;
+        if byte == 0 {
+            zeros += 1;
+            if zeros == 0xFF {
+                output.push(0);
+                output.push(zeros);
+                zeros = 0;
+            }
+            continue;
+        }
+        if zeros > 0 {
+            output.push(0);
+            output.push(zeros);
+            zeros = 0;
+        }
+        output.push(byte);
+    }
+    if zeros > 0 {
+        output.push(0);
+        output.push(zeros);
+    }
+    output
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {//xors a compressed delta back into state, turning one snapshot into its neighbour
+    let mut position = 0;
+    let mut bytes = delta.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == 0 {
+            position += *bytes.next().unwrap() as usize;
+            continue;
+        }
+        state[position] ^= byte;
+        position += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::Rom;
+
+    #[test]
+    fn delta_round_trips() {
+        let old: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut new = old.clone();
+        new[0] ^= 0x5A;
+        new[300] = 0; //a changed byte between zero runs longer than one length byte
+        new[999] = 0xFF;
+
+        let delta = compress_delta(&old, &new);
+        assert!(delta.len() < 20);
+        let mut state = old.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, new);
+        apply_delta(&mut state, &delta); //xor deltas undo themselves
+        assert_eq!(state, old);
+    }
+
+    #[test]
+    fn identical_states_give_only_zero_runs() {
+        let state = vec![0x42; 600];
+        let delta = compress_delta(&state, &state);
+        assert_eq!(delta, [0, 255, 0, 255, 0, 90]);
+        let mut restored = state.clone();
+        apply_delta(&mut restored, &delta);
+        assert_eq!(restored, state);
+    }
+
+    #[test]
+    fn step_back_starts_at_the_previous_snapshot() {
+        let mut rom = Rom::default();
+        rom.data = vec![0; 0x8000];
+        let mut cpu = Cpu::new(rom);
+        let mut rewind = Rewind::new(1.0, 60.0, 1);
+        let mut snapshots = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..1000 {
+                cpu.exec();
+            }
+            rewind.record(&cpu);
+            snapshots.push(cpu.save_state());
+        }
+
+        rewind.step_back(&mut cpu);
+        assert_eq!(cpu.save_state(), snapshots[1]);
+        rewind.step_back(&mut cpu);
+        assert_eq!(cpu.save_state(), snapshots[0]);
+        rewind.step_back(&mut cpu);
+        assert_eq!(cpu.save_state(), snapshots[0]);
+    }
+}
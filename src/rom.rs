@@ -2,6 +2,7 @@ use std::fs::File;
 use std::io::{Error, Read};
 
 const ROM_BANK_SIZE: u64 = 32768;
+const RAM_BANK_SIZE: usize = 8192;
 const VALID_LOGO: [u8; 48] = [0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 
 							  0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 
 							  0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E];
@@ -61,7 +62,7 @@ impl Rom {
 		Ok(Rom {
 			title: title,
 			manufacturing_code: manufacturing_code,
-			cgb: data[0x0134],
+			cgb: data[0x0143],
 			licensee_code: licensee_code,
 			sgb: data[0x0146],
 			cartridge_type: data[0x0147],
@@ -70,4 +71,23 @@ impl Rom {
 			data: data
 		})
 	}
+	pub fn is_cgb(&self) -> bool {
+		self.cgb & 0x80 != 0
+	}
+	pub fn has_battery(&self) -> bool {
+		matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF)
+	}
+	pub fn logo_at(&self, offset: usize) -> bool {
+		offset + VALID_LOGO.len() <= self.data.len() && self.data[offset..offset + VALID_LOGO.len()] == VALID_LOGO
+	}
+	pub fn ram_size_bytes(&self) -> usize {
+		match self.ram_size {
+			0x01 => 2048, //unofficial, used by a few homebrew carts
+			0x02 => RAM_BANK_SIZE,
+			0x03 => RAM_BANK_SIZE * 4,
+			0x04 => RAM_BANK_SIZE * 16,
+			0x05 => RAM_BANK_SIZE * 8,
+			_ => 0
+		}
+	}
 }
\ No newline at end of file
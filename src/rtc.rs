@@ -0,0 +1,224 @@
+use std::io::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::{self, StateReader, StateWriter};
+
+const DOTS_PER_SECOND: u32 = 4_194_304; //the rtc crystal doesn't speed up in double speed, so it counts dots rather than m-cycles
+const DAY_HIGH_HALT: u8 = 0b01000000;
+const DAY_HIGH_CARRY: u8 = 0b10000000;
+
+pub struct Rtc {//mbc3 real-time clock
+    pub seconds: u8, //$08
+    pub minutes: u8, //$09
+    pub hours: u8, //$0A
+    pub days: u16, //$0B and bit 0 of $0C
+    pub halted: bool, //bit 6 of $0C
+    pub day_carry: bool, //bit 7 of $0C
+    pub latched: [u8; 5], //values visible to the cpu, copied from the live registers on latch
+    latch_armed: bool, //a 0 was written to $6000-$7FFF, a 1 will latch
+    cycles: u32, //dots since the last emulated second
+    wall_clock: bool, //follow host time instead of emulated time
+    last_update: u64, //unix time in seconds of the last wall clock sync
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            day_carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            cycles: 0,
+            wall_clock: false,
+            last_update: unix_time(),
+        }
+    }
+    pub fn set_wall_clock(&mut self, enabled: bool) {
+        self.wall_clock = enabled;
+        self.last_update = unix_time();
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.seconds);
+        writer.u8(self.minutes);
+        writer.u8(self.hours);
+        writer.u16(self.days);
+        writer.bool(self.halted);
+        writer.bool(self.day_carry);
+        writer.bytes(&self.latched);
+        writer.bool(self.latch_armed);
+        writer.u32(self.cycles);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.seconds = reader.u8()?;
+        self.minutes = reader.u8()?;
+        self.hours = reader.u8()?;
+        self.days = reader.u16()? & 0x1FF;
+        self.halted = reader.bool()?;
+        self.day_carry = reader.bool()?;
+        reader.bytes(&mut self.latched)?;
+        self.latch_armed = reader.bool()?;
+        self.cycles = reader.u32()?;
+        self.last_update = unix_time(); //wall clock time spent before the load is not replayed
+        state::check(self.cycles < DOTS_PER_SECOND, "rtc cycle counter")
+    }
+    pub fn tick(&mut self, dots: u32) {
+        self.cycles += dots;
+        if self.cycles < DOTS_PER_SECOND {
+            return;
+        }
+        self.cycles -= DOTS_PER_SECOND;
+        if self.wall_clock {//host time is only checked once per emulated second
+            let now = unix_time();
+            if now > self.last_update {
+                self.advance(now - self.last_update);
+                self.last_update = now;
+            }
+            return;
+        }
+        self.advance(1);
+    }
+    pub fn advance(&mut self, seconds: u64) {//also used to catch up on time that passed while the emulator was closed
+        if self.halted || seconds == 0 {
+            return;
+        }
+        if seconds == 1 {
+            self.increment();
+            return;
+        }
+        if self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24 {//out of range values need the exact overflow behavior
+            for _ in 0..seconds {
+                self.increment();
+            }
+            return;
+        }
+        let total = self.seconds as u64 + 60 * self.minutes as u64 + 3600 * self.hours as u64
+            + 86400 * self.days as u64 + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+    pub fn write_latch(&mut self, data: u8) {
+        if self.latch_armed && data == 1 {
+            self.latched = [self.seconds, self.minutes, self.hours, self.days as u8, self.day_high()];
+        }
+        self.latch_armed = data == 0;
+    }
+    pub fn read(&self, register: u8) -> u8 {
+        let masks = [0b00111111, 0b00111111, 0b00011111, 0b11111111, 0b11000001];
+        let index = (register - 0x08) as usize;
+        self.latched[index] & masks[index]
+    }
+    pub fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0x08 => {
+                self.seconds = data & 0b00111111;
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = data & 0b00111111,
+            0x0A => self.hours = data & 0b00011111,
+            0x0B => self.days = (self.days & 0x100) | data as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((data as u16 & 1) << 8);
+                self.halted = data & DAY_HIGH_HALT != 0;
+                self.day_carry = data & DAY_HIGH_CARRY != 0;
+            }
+        }
+        let index = (register - 0x08) as usize;
+        self.latched[index] = data; //writes are visible immediately
+    }
+    pub fn day_high(&self) -> u8 {
+        let mut day_high = (self.days >> 8) as u8;
+        if self.halted {
+            day_high |= DAY_HIGH_HALT;
+        }
+        if self.day_carry {
+            day_high |= DAY_HIGH_CARRY;
+        }
+        day_high
+    }
+
+    pub fn save_trailer(&self) -> [u8; 48] {//the 48-byte bgb/vba format appended to the ram in .sav files
+        let mut trailer = [0; 48];
+        let live = [self.seconds, self.minutes, self.hours, self.days as u8, self.day_high()];
+        for i in 0..5 {
+            trailer[i * 4] = live[i];
+            trailer[20 + i * 4] = self.latched[i];
+        }
+        trailer[40..48].copy_from_slice(&unix_time().to_le_bytes());
+        trailer
+    }
+    pub fn load_save_trailer(&mut self, trailer: &[u8]) {//accepts the 48-byte format and the older 44-byte one with a 32-bit timestamp
+        self.seconds = trailer[0] & 0b00111111;
+        self.minutes = trailer[4] & 0b00111111;
+        self.hours = trailer[8] & 0b00011111;
+        self.days = ((trailer[16] as u16 & 1) << 8) | trailer[12] as u16;
+        self.halted = trailer[16] & DAY_HIGH_HALT != 0;
+        self.day_carry = trailer[16] & DAY_HIGH_CARRY != 0;
+        for i in 0..5 {
+            self.latched[i] = trailer[20 + i * 4];
+        }
+
+        let mut timestamp = [0; 8];
+        let timestamp_length = trailer.len() - 40;
+        timestamp[..timestamp_length].copy_from_slice(&trailer[40..]);
+        let saved_at = u64::from_le_bytes(timestamp);
+        let now = unix_time();
+        if now > saved_at {//the clock kept running on the cartridge battery while the emulator was closed
+            self.advance(now - saved_at);
+        }
+        self.last_update = now;
+    }
+
+    fn increment(&mut self) {//one second, following the counters' wraparound for out of range values
+        self.seconds = (self.seconds + 1) & 0b00111111;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0b00111111;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0b00011111;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_cycle_counter_is_rejected() {
+        let mut writer = StateWriter::new();
+        Rtc::new().save_state(&mut writer);
+        let mut data = writer.into_bytes();
+        let length = data.len();
+        data[length - 4..].copy_from_slice(&u32::MAX.to_le_bytes()); //cycles is saved last
+
+        let error = Rtc::new().load_state(&mut StateReader::new(&data)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
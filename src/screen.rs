@@ -1,36 +1,112 @@
+use std::io::Error;
 
+use crate::state::{StateReader, StateWriter};
 
 pub const TEST_TILE: Tile = Tile {data: [0x3C, 0x7E, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x5E, 0x7E, 0x0A, 0x7C, 0x56, 0x38, 0x7C]};
 
+const TILEMAP_OFFSET: usize = 0x1800; //$9800 relative to the start of vram
+
+#[derive(Default, Copy, Clone)]
+pub struct Sprite {//one 4-byte oam entry
+    pub y: u8, //screen y + 16
+    pub x: u8, //screen x + 8
+    pub tile: u8,
+    pub attributes: u8, //bit 7 bg priority, bit 6 y flip, bit 5 x flip, bit 4 palette
+}
+
 #[derive(Default, Copy, Clone)]
 pub struct Tile {
     pub data: [u8; 16]
 }
 
 pub struct Screen {
-    pub tiledata: [[Tile; 128]; 3], //$8000-$97FF
-    pub tilemaps: [[[u8; 32]; 32]; 2], //$9800-$9FFF
+    pub vram: [u8; 0x2000], //$8000-$9FFF, tiledata followed by the two tilemaps
+    pub oam: [u8; 0xA0], //$FE00-$FE9F
     pub lcdc: u8, //$FF40
+    pub stat: u8, //$FF41
     pub scy: u8, //$FF42
     pub scx: u8, //$FF43
+    pub ly: u8, //$FF44
+    pub lyc: u8, //$FF45
+    pub dma: u8, //$FF46
+    pub bgp: u8, //$FF47
+    pub obp0: u8, //$FF48
+    pub obp1: u8, //$FF49
+    pub wy: u8, //$FF4A
+    pub wx: u8, //$FF4B
 }
 
 impl Screen {
     pub fn new() -> Screen {
-        Screen {
-            tiledata: [[Default::default(); 128]; 3],
-            tilemaps: [[[0; 32]; 32]; 2],
-            lcdc: 0,
+        Screen {//emulates behavior of DMG after the boot rom
+            vram: [0; 0x2000],
+            oam: [0; 0xA0],
+            lcdc: 0x91,
+            stat: 0x02,
             scy: 0,
             scx: 0,
+            ly: 0,
+            lyc: 0,
+            dma: 0xFF,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+        }
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.vram);
+        writer.bytes(&self.oam);
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.dma, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            writer.u8(register);
+        }
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        reader.bytes(&mut self.vram)?;
+        reader.bytes(&mut self.oam)?;
+        for register in [&mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+                         &mut self.dma, &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx] {
+            *register = reader.u8()?;
         }
+        Ok(())
     }
     pub fn test_screen() -> Screen {
         let mut screen = Screen::new();
-        screen.tiledata[0][0] = TEST_TILE;
+        screen.vram[0..16].copy_from_slice(&TEST_TILE.data);
         screen.lcdc = 0b10010011;
         screen.scx = 0b00000110;
         screen.scy = 0b00000110;
-        return screen;
+        screen
     }
-}
\ No newline at end of file
+    pub fn sprite(&self, index: usize) -> Sprite {//index is 0-39
+        let entry = &self.oam[index * 4..index * 4 + 4];
+        Sprite {
+            y: entry[0],
+            x: entry[1],
+            tile: entry[2],
+            attributes: entry[3],
+        }
+    }
+    pub fn tile(&self, index: usize) -> Tile {//index counts 16-byte tiles from $8000, 0-383
+        let mut tile: Tile = Default::default();
+        tile.data.copy_from_slice(&self.vram[index * 16..index * 16 + 16]);
+        tile
+    }
+    pub fn bg_tile_index(&self, tile_id: u8) -> usize {//bg and window tile ids are unsigned from $8000, or signed from $9000
+        if self.lcdc & 0b00010000 != 0 {
+            return tile_id as usize;
+        }
+        (256 + tile_id as i8 as isize) as usize
+    }
+    pub fn tile_pixel(&self, index: usize, x: usize, y: usize) -> u8 {//2-bit color index of one pixel of a tile
+        let low_byte = self.vram[index * 16 + y * 2];
+        let high_byte = self.vram[index * 16 + y * 2 + 1];
+        let high_bit = ((high_byte >> (7 - x)) & 1) << 1;
+        let low_bit = (low_byte >> (7 - x)) & 1;
+        high_bit | low_bit
+    }
+    pub fn tilemap(&self, map: usize, row: usize, column: usize) -> u8 {//map 0 is $9800, map 1 is $9C00
+        self.vram[TILEMAP_OFFSET + map * 0x400 + row * 32 + column]
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+const MULTIPLIERS: [f64; 5] = [0.25, 0.5, 1.0, 2.0, 4.0]; //steps for the faster and slower hotkeys
+
+pub struct Speed {//emulation speed relative to real hardware
+    multiplier: f64,
+    limited: bool, //false runs as fast as the host allows
+}
+
+impl Speed {
+    pub fn new() -> Speed {
+        Speed {
+            multiplier: 1.0,
+            limited: true,
+        }
+    }
+    pub fn parse(value: &str) -> Option<Speed> {//a multiplier within the hotkey steps such as 0.5 or 2, or "uncapped"
+        if value == "uncapped" {
+            return Some(Speed {multiplier: 1.0, limited: false});
+        }
+        let multiplier: f64 = value.parse().ok()?;
+        if !(MULTIPLIERS[0]..=MULTIPLIERS[MULTIPLIERS.len() - 1]).contains(&multiplier) {//also rejects nan
+            return None;
+        }
+        Some(Speed {multiplier, limited: true})
+    }
+    pub fn limited(&self) -> bool {
+        self.limited
+    }
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier
+    }
+    pub fn frame_duration(&self, base: Duration) -> Duration {
+        base.div_f64(self.multiplier)
+    }
+    pub fn faster(&mut self) {
+        self.limited = true;
+        self.multiplier = *MULTIPLIERS.iter().find(|&&step| step > self.multiplier).unwrap_or(MULTIPLIERS.last().unwrap());
+    }
+    pub fn slower(&mut self) {
+        self.limited = true;
+        self.multiplier = *MULTIPLIERS.iter().rev().find(|&&step| step < self.multiplier).unwrap_or(&MULTIPLIERS[0]);
+    }
+    pub fn toggle_limiter(&mut self) {
+        self.limited = !self.limited;
+    }
+    pub fn label(&self) -> String {//for the window title
+        if !self.limited {
+            return String::from("uncapped");
+        }
+        format!("{}x", self.multiplier)
+    }
+}
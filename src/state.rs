@@ -0,0 +1,96 @@
+use std::io::{Error, ErrorKind};
+
+pub const STATE_MAGIC: [u8; 8] = *b"JAGESTAT";
+pub const STATE_VERSION: u32 = 1; //bump whenever the layout below any save_state changes
+
+pub struct StateWriter {//little-endian binary writer for save states
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+        }
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+}
+
+pub struct StateReader<'a> {//reads back what StateWriter wrote, in the same order
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data,
+            position: 0,
+        }
+    }
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn bytes(&mut self, output: &mut [u8]) -> Result<(), Error> {
+        output.copy_from_slice(self.take(output.len())?);
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.position + length > self.data.len() {
+            return Err(invalid_state("Save state is truncated"));
+        }
+        let slice = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(slice)
+    }
+}
+
+pub fn invalid_state(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+pub fn check(valid: bool, field: &str) -> Result<(), Error> {//rejects loaded values the emulator can't run from
+    if valid {
+        return Ok(());
+    }
+    Err(invalid_state(&format!("Save state has an invalid {}", field)))
+}
@@ -0,0 +1,116 @@
+use std::io::Error;
+
+use crate::state::{StateReader, StateWriter};
+use crate::interrupts::{Interrupt, Interrupts};
+
+const TAC_ENABLE: u8 = 0b00000100;
+
+pub struct Timer {
+    counter: u16, //internal 16-bit counter in t-cycles, DIV is the upper byte
+    pub tima: u8, //$FF05
+    pub tma: u8, //$FF06
+    tac: u8, //$FF07
+    overflow_pending: bool, //tima overflowed last m-cycle, it reads 0 until tma is reloaded
+    reloading: bool, //tma was copied into tima this m-cycle, writes to tima are ignored
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {//emulates behavior of DMG after the boot rom
+            counter: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_pending: false,
+            reloading: false,
+        }
+    }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.u8(self.tima);
+        writer.u8(self.tma);
+        writer.u8(self.tac);
+        writer.bool(self.overflow_pending);
+        writer.bool(self.reloading);
+    }
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), Error> {
+        self.counter = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()? & 0b00000111;
+        self.overflow_pending = reader.bool()?;
+        self.reloading = reader.bool()?;
+        Ok(())
+    }
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+    pub fn read_div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+    pub fn read_tac(&self) -> u8 {
+        self.tac | 0b11111000
+    }
+    pub fn write_div(&mut self) {//any write resets the whole counter, which can be a falling edge
+        let old_bit = self.timer_bit();
+        self.counter = 0;
+        if old_bit {
+            self.increment_tima();
+        }
+    }
+    pub fn write_tima(&mut self, data: u8) {
+        if self.reloading {
+            return;
+        }
+        self.overflow_pending = false; //writing during the delay cancels the reload and interrupt
+        self.tima = data;
+    }
+    pub fn write_tma(&mut self, data: u8) {
+        self.tma = data;
+        if self.reloading {
+            self.tima = data;
+        }
+    }
+    pub fn write_tac(&mut self, data: u8) {//disabling the timer or switching clocks can also be a falling edge
+        let old_bit = self.timer_bit();
+        self.tac = data & 0b00000111;
+        if old_bit && !self.timer_bit() {
+            self.increment_tima();
+        }
+    }
+    pub fn tick(&mut self, m_cycles: u32, interrupts: &mut Interrupts) {
+        for _ in 0..m_cycles {
+            self.reloading = false;
+            if self.overflow_pending {
+                self.overflow_pending = false;
+                self.tima = self.tma;
+                self.reloading = true;
+                interrupts.request(Interrupt::Timer);
+            }
+
+            let old_bit = self.timer_bit();
+            self.counter = self.counter.wrapping_add(4);
+            if old_bit && !self.timer_bit() {
+                self.increment_tima();
+            }
+        }
+    }
+
+    fn timer_bit(&self) -> bool {//counter bit selected by tac, anded with the enable bit
+        let bit = match self.tac & 0b00000011 {
+            0 => 9, //4096 Hz
+            1 => 3, //262144 Hz
+            2 => 5, //65536 Hz
+            _ => 7, //16384 Hz
+        };
+        self.tac & TAC_ENABLE != 0 && (self.counter >> bit) & 1 != 0
+    }
+    fn increment_tima(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.overflow_pending = true;
+            return;
+        }
+        self.tima += 1;
+    }
+}